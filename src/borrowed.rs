@@ -0,0 +1,709 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use byte::{check_len, BytesExt, TryRead};
+
+use crate::defs::{
+    is_reserved_type, read_header, read_len, ByteBuf, ClientId, ConnAck, Connect, DecodeError,
+    Disconnect, Flags, GwAddress, GwInfo, Message, PingReq, PingResp, PubAck, Publish, RegAck,
+    Register, ReturnCode, SearchGw, SubAck, Subscribe, TopicNameOrId, Unknown, UnsubAck,
+    Unsubscribe, WillTopic, MAX_EXTENSION_LEN,
+};
+
+// Each `*Ref` checks its frame in `TryRead` and reads its fields in
+// `read_body`. The owned types parse through these and convert with
+// `TryFrom`, which is where capacity limits apply.
+
+/// Reads a 1-byte length field and the type byte, failing with `err` if the
+/// length is below `min_len`. Returns the length.
+fn read_frame(
+    bytes: &[u8],
+    offset: &mut usize,
+    min_len: usize,
+    err: &'static str,
+) -> byte::Result<usize> {
+    let len = bytes.read::<u8>(offset)? as usize;
+    check_len(bytes, len)?;
+    if len < min_len {
+        return Err(byte::Error::BadInput { err });
+    }
+    *offset += 1; // msg type
+    Ok(len)
+}
+
+/// Like [`read_frame`] for messages that may use the 3-byte length field.
+/// `min_body` is the number of bytes required after the type byte.
+fn read_long_frame(
+    bytes: &[u8],
+    offset: &mut usize,
+    min_body: usize,
+    err: &'static str,
+) -> byte::Result<usize> {
+    let len = read_len(bytes, offset)?;
+    check_len(bytes, len)?;
+    if len < *offset + 1 + min_body {
+        return Err(byte::Error::BadInput { err });
+    }
+    *offset += 1; // msg type
+    Ok(len)
+}
+
+/// Reads the rest of `bytes`, for a trailing variable-length field.
+fn read_rest<'a>(bytes: &'a [u8], offset: &mut usize) -> byte::Result<&'a [u8]> {
+    bytes.read_with(offset, byte::ctx::Bytes::Len(bytes.len() - *offset))
+}
+
+/// Reads the rest of `bytes` as extension bytes, up to
+/// [`MAX_EXTENSION_LEN`]. Any beyond that are left unread, so strict parsing
+/// reports them as a length mismatch.
+fn read_extension<'a>(bytes: &'a [u8], offset: &mut usize) -> byte::Result<&'a [u8]> {
+    let len = (bytes.len() - *offset).min(MAX_EXTENSION_LEN);
+    bytes.read_with(offset, byte::ctx::Bytes::Len(len))
+}
+
+/// Copies a borrowed field into its owned type, whose length-taking
+/// `TryRead` rejects a field over capacity.
+fn copy_field<'a, T: TryRead<'a, usize>>(bytes: &'a [u8]) -> byte::Result<T> {
+    bytes.read_with(&mut 0, bytes.len())
+}
+
+/// Copies extension bytes read by [`read_extension`] into their owned form.
+fn copy_extension(extra: &[u8]) -> byte::Result<ByteBuf<MAX_EXTENSION_LEN>> {
+    ByteBuf::from_slice(extra).map_err(|_e| byte::Error::BadInput {
+        err: "extension longer than MAX_EXTENSION_LEN",
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GwInfoRef<'a> {
+    pub gw_id: u8,
+    pub gw_add: Option<&'a [u8]>,
+}
+
+impl<'a> GwInfoRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let gw_id = bytes.read(offset)?;
+        let gw_add = if bytes.len() > *offset {
+            Some(read_rest(bytes, offset)?)
+        } else {
+            None
+        };
+        Ok(GwInfoRef { gw_id, gw_add })
+    }
+}
+
+impl<'a> TryRead<'a> for GwInfoRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_frame(bytes, offset, 3, "GwInfo len must be >= 3 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<GwInfoRef<'_>> for GwInfo {
+    type Error = byte::Error;
+
+    fn try_from(msg: GwInfoRef<'_>) -> byte::Result<Self> {
+        Ok(GwInfo {
+            gw_id: msg.gw_id,
+            gw_add: msg.gw_add.map(GwAddress::try_from).transpose()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectRef<'a> {
+    pub flags: Flags,
+    pub duration: u16,
+    pub client_id: &'a str,
+}
+
+impl<'a> ConnectRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let flags = bytes.read(offset)?;
+        bytes.read::<u8>(offset)?; // protocol id
+        let duration = bytes.read_with(offset, byte::ctx::BE)?;
+        Ok(ConnectRef {
+            flags,
+            duration,
+            client_id: bytes.read_with(offset, byte::ctx::Str::Len(bytes.len() - *offset))?,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for ConnectRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_frame(bytes, offset, 6, "Connect len must be >= 6 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl<const ID: usize> TryFrom<ConnectRef<'_>> for Connect<ID> {
+    type Error = byte::Error;
+
+    fn try_from(msg: ConnectRef<'_>) -> byte::Result<Self> {
+        Ok(Connect {
+            flags: msg.flags,
+            duration: msg.duration,
+            client_id: copy_field(msg.client_id.as_bytes())?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnAckRef<'a> {
+    pub code: ReturnCode,
+    pub extra: &'a [u8],
+}
+
+impl<'a> ConnAckRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let code = bytes.read(offset)?;
+        let extra = read_extension(bytes, offset)?;
+        Ok(ConnAckRef { code, extra })
+    }
+}
+
+impl<'a> TryRead<'a> for ConnAckRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_frame(bytes, offset, 3, "ConnAck len must be >= 3 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<ConnAckRef<'_>> for ConnAck {
+    type Error = byte::Error;
+
+    fn try_from(msg: ConnAckRef<'_>) -> byte::Result<Self> {
+        Ok(ConnAck {
+            code: msg.code,
+            extra: copy_extension(msg.extra)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WillTopicRef<'a> {
+    pub flags: Flags,
+    pub topic_name: &'a [u8],
+}
+
+impl<'a> WillTopicRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        if *offset == bytes.len() {
+            return Ok(WillTopicRef {
+                flags: Flags::default(),
+                topic_name: &[],
+            });
+        }
+        let flags = bytes.read(offset)?;
+        Ok(WillTopicRef {
+            flags,
+            topic_name: read_rest(bytes, offset)?,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for WillTopicRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_frame(bytes, offset, 2, "WillTopic len must be >= 2 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<WillTopicRef<'_>> for WillTopic {
+    type Error = byte::Error;
+
+    fn try_from(msg: WillTopicRef<'_>) -> byte::Result<Self> {
+        Ok(WillTopic {
+            flags: msg.flags,
+            topic_name: copy_field(msg.topic_name)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegisterRef<'a> {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub topic_name: &'a [u8],
+}
+
+impl<'a> RegisterRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let topic_id = bytes.read_with(offset, byte::ctx::BE)?;
+        let msg_id = bytes.read_with(offset, byte::ctx::BE)?;
+        Ok(RegisterRef {
+            topic_id,
+            msg_id,
+            topic_name: read_rest(bytes, offset)?,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for RegisterRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_long_frame(bytes, offset, 4, "Register len too short for header")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<RegisterRef<'_>> for Register {
+    type Error = byte::Error;
+
+    fn try_from(msg: RegisterRef<'_>) -> byte::Result<Self> {
+        Ok(Register {
+            topic_id: msg.topic_id,
+            msg_id: msg.msg_id,
+            topic_name: copy_field(msg.topic_name)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegAckRef<'a> {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub code: ReturnCode,
+    pub extra: &'a [u8],
+}
+
+impl<'a> RegAckRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let topic_id = bytes.read_with(offset, byte::ctx::BE)?;
+        let msg_id = bytes.read_with(offset, byte::ctx::BE)?;
+        let code = bytes.read(offset)?;
+        let extra = read_extension(bytes, offset)?;
+        Ok(RegAckRef {
+            topic_id,
+            msg_id,
+            code,
+            extra,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for RegAckRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_frame(bytes, offset, 7, "RegAck len must be >= 7 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<RegAckRef<'_>> for RegAck {
+    type Error = byte::Error;
+
+    fn try_from(msg: RegAckRef<'_>) -> byte::Result<Self> {
+        Ok(RegAck {
+            topic_id: msg.topic_id,
+            msg_id: msg.msg_id,
+            code: msg.code,
+            extra: copy_extension(msg.extra)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PublishRef<'a> {
+    pub flags: Flags,
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> PublishRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        Ok(PublishRef {
+            flags: bytes.read(offset)?,
+            topic_id: bytes.read_with(offset, byte::ctx::BE)?,
+            msg_id: bytes.read_with(offset, byte::ctx::BE)?,
+            data: read_rest(bytes, offset)?,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for PublishRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_long_frame(bytes, offset, 5, "Publish len must be >= 7 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<PublishRef<'_>> for Publish {
+    type Error = byte::Error;
+
+    fn try_from(msg: PublishRef<'_>) -> byte::Result<Self> {
+        Ok(Publish {
+            flags: msg.flags,
+            topic_id: msg.topic_id,
+            msg_id: msg.msg_id,
+            data: copy_field(msg.data)?,
+        })
+    }
+}
+
+/// Borrowed form of [`TopicNameOrId`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TopicNameOrIdRef<'a> {
+    Name(&'a [u8]),
+    Id(u16),
+}
+
+impl<'a> TryRead<'a, (Flags, usize)> for TopicNameOrIdRef<'a> {
+    fn try_read(bytes: &'a [u8], ctx: (Flags, usize)) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        Ok((
+            if ctx.0.topic_id_type() == 0 {
+                Self::Name(bytes.read_with(offset, byte::ctx::Bytes::Len(ctx.1))?)
+            } else {
+                Self::Id(bytes.read_with(offset, byte::ctx::BE)?)
+            },
+            *offset,
+        ))
+    }
+}
+
+impl TryFrom<TopicNameOrIdRef<'_>> for TopicNameOrId {
+    type Error = byte::Error;
+
+    fn try_from(topic: TopicNameOrIdRef<'_>) -> byte::Result<Self> {
+        Ok(match topic {
+            TopicNameOrIdRef::Name(name) => TopicNameOrId::Name(copy_field(name)?),
+            TopicNameOrIdRef::Id(id) => TopicNameOrId::Id(id),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubscribeRef<'a> {
+    pub flags: Flags,
+    pub msg_id: u16,
+    pub topic: TopicNameOrIdRef<'a>,
+}
+
+impl<'a> SubscribeRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let flags = bytes.read(offset)?;
+        let msg_id = bytes.read_with(offset, byte::ctx::BE)?;
+        Ok(SubscribeRef {
+            flags,
+            msg_id,
+            topic: bytes.read_with(offset, (flags, bytes.len() - *offset))?,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for SubscribeRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_long_frame(bytes, offset, 3, "Subscribe len too short for header")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<SubscribeRef<'_>> for Subscribe {
+    type Error = byte::Error;
+
+    fn try_from(msg: SubscribeRef<'_>) -> byte::Result<Self> {
+        Ok(Subscribe {
+            flags: msg.flags,
+            msg_id: msg.msg_id,
+            topic: msg.topic.try_into()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UnsubscribeRef<'a> {
+    pub flags: Flags,
+    pub msg_id: u16,
+    pub topic: TopicNameOrIdRef<'a>,
+}
+
+impl<'a> UnsubscribeRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let flags = bytes.read(offset)?;
+        let msg_id = bytes.read_with(offset, byte::ctx::BE)?;
+        Ok(UnsubscribeRef {
+            flags,
+            msg_id,
+            topic: bytes.read_with(offset, (flags, bytes.len() - *offset))?,
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for UnsubscribeRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_long_frame(bytes, offset, 3, "Unsubscribe len too short for header")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<UnsubscribeRef<'_>> for Unsubscribe {
+    type Error = byte::Error;
+
+    fn try_from(msg: UnsubscribeRef<'_>) -> byte::Result<Self> {
+        Ok(Unsubscribe {
+            flags: msg.flags,
+            msg_id: msg.msg_id,
+            topic: msg.topic.try_into()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingReqRef<'a> {
+    pub client_id: Option<&'a str>,
+}
+
+impl<'a> PingReqRef<'a> {
+    pub(crate) fn read_body(bytes: &'a [u8], offset: &mut usize) -> byte::Result<Self> {
+        let client_id = if bytes.len() > *offset {
+            Some(bytes.read_with(offset, byte::ctx::Str::Len(bytes.len() - *offset))?)
+        } else {
+            None
+        };
+        Ok(PingReqRef { client_id })
+    }
+}
+
+impl<'a> TryRead<'a> for PingReqRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_frame(bytes, offset, 2, "Len must be at least 2 bytes")?;
+        Ok((Self::read_body(&bytes[..len], offset)?, *offset))
+    }
+}
+
+impl TryFrom<PingReqRef<'_>> for PingReq {
+    type Error = byte::Error;
+
+    fn try_from(msg: PingReqRef<'_>) -> byte::Result<Self> {
+        Ok(PingReq {
+            client_id: msg
+                .client_id
+                .map(|id| copy_field::<ClientId>(id.as_bytes()))
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UnknownRef<'a> {
+    pub msg_type: u8,
+    pub body: &'a [u8],
+}
+
+impl<'a> TryRead<'a> for UnknownRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len = read_len(bytes, offset)?;
+        check_len(bytes, len)?;
+        let msg_type = bytes.read(offset)?;
+        let body = read_rest(&bytes[..len], offset)?;
+        Ok((UnknownRef { msg_type, body }, *offset))
+    }
+}
+
+impl TryFrom<UnknownRef<'_>> for Unknown {
+    type Error = byte::Error;
+
+    fn try_from(msg: UnknownRef<'_>) -> byte::Result<Self> {
+        Ok(Unknown {
+            msg_type: msg.msg_type,
+            body: heapless::Vec::from_slice(msg.body).map_err(|_e| byte::Error::BadInput {
+                err: "Unknown message body longer than 256 bytes",
+            })?,
+        })
+    }
+}
+
+/// A [`Message`] whose variable-length fields borrow from the buffer it was
+/// parsed from. Fixed-size messages are held as their owned types. Parsing
+/// checks the framing as [`Message`] does but not the owned types'
+/// capacities; converting with `Message::try_from` fails if a field doesn't
+/// fit.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageRef<'a> {
+    SearchGw(SearchGw),
+    GwInfo(GwInfoRef<'a>),
+    Connect(ConnectRef<'a>),
+    ConnAck(ConnAckRef<'a>),
+    WillTopic(WillTopicRef<'a>),
+    Register(RegisterRef<'a>),
+    RegAck(RegAckRef<'a>),
+    Publish(PublishRef<'a>),
+    PubAck(PubAck),
+    Subscribe(SubscribeRef<'a>),
+    SubAck(SubAck),
+    Unsubscribe(UnsubscribeRef<'a>),
+    UnsubAck(UnsubAck),
+    PingReq(PingReqRef<'a>),
+    PingResp(PingResp),
+    Disconnect(Disconnect),
+    Unknown(UnknownRef<'a>),
+}
+
+impl TryFrom<&MessageRef<'_>> for Message {
+    type Error = byte::Error;
+
+    fn try_from(msg: &MessageRef<'_>) -> byte::Result<Self> {
+        Ok(match msg {
+            MessageRef::SearchGw(msg) => Message::SearchGw(*msg),
+            MessageRef::GwInfo(msg) => Message::GwInfo((*msg).try_into()?),
+            MessageRef::Connect(msg) => Message::Connect((*msg).try_into()?),
+            MessageRef::ConnAck(msg) => Message::ConnAck((*msg).try_into()?),
+            MessageRef::WillTopic(msg) => Message::WillTopic((*msg).try_into()?),
+            MessageRef::Register(msg) => Message::Register((*msg).try_into()?),
+            MessageRef::RegAck(msg) => Message::RegAck((*msg).try_into()?),
+            MessageRef::Publish(msg) => Message::Publish((*msg).try_into()?),
+            MessageRef::PubAck(msg) => Message::PubAck(msg.clone()),
+            MessageRef::Subscribe(msg) => Message::Subscribe((*msg).try_into()?),
+            MessageRef::SubAck(msg) => Message::SubAck(msg.clone()),
+            MessageRef::Unsubscribe(msg) => Message::Unsubscribe((*msg).try_into()?),
+            MessageRef::UnsubAck(msg) => Message::UnsubAck(msg.clone()),
+            MessageRef::PingReq(msg) => Message::PingReq((*msg).try_into()?),
+            MessageRef::PingResp(msg) => Message::PingResp(*msg),
+            MessageRef::Disconnect(msg) => Message::Disconnect(*msg),
+            MessageRef::Unknown(msg) => Message::Unknown((*msg).try_into()?),
+        })
+    }
+}
+
+impl<'a> TryRead<'a> for MessageRef<'a> {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        // Not increasing offset because some messages needs access to len.
+        Ok((
+            match read_header(bytes)? {
+                0x01 => MessageRef::SearchGw(bytes.read(offset)?),
+                0x02 => MessageRef::GwInfo(bytes.read(offset)?),
+                0x04 => MessageRef::Connect(bytes.read(offset)?),
+                0x05 => MessageRef::ConnAck(bytes.read(offset)?),
+                0x07 => MessageRef::WillTopic(bytes.read(offset)?),
+                0x0a => MessageRef::Register(bytes.read(offset)?),
+                0x0b => MessageRef::RegAck(bytes.read(offset)?),
+                0x0c => MessageRef::Publish(bytes.read(offset)?),
+                0x0d => MessageRef::PubAck(bytes.read(offset)?),
+                0x12 => MessageRef::Subscribe(bytes.read(offset)?),
+                0x13 => MessageRef::SubAck(bytes.read(offset)?),
+                0x14 => MessageRef::Unsubscribe(bytes.read(offset)?),
+                0x15 => MessageRef::UnsubAck(bytes.read(offset)?),
+                0x16 => MessageRef::PingReq(bytes.read(offset)?),
+                0x17 => MessageRef::PingResp(bytes.read(offset)?),
+                0x18 => MessageRef::Disconnect(bytes.read(offset)?),
+                t if is_reserved_type(t) => MessageRef::Unknown(bytes.read(offset)?),
+                t => return Err(DecodeError::UnknownMessageType(t).into()),
+            },
+            *offset,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defs::QoS;
+
+    #[test]
+    fn message_ref_aliases_input() {
+        let mut buf = [0u8; 64];
+        let msg = Message::publish(Flags::default(), 1, 2, "payload");
+        let len = msg.encode(&mut buf).unwrap();
+        let buf = &buf[..len];
+        let (parsed, parsed_len) = MessageRef::try_read(buf, ()).unwrap();
+        assert_eq!(parsed_len, len);
+        match parsed {
+            MessageRef::Publish(publish) => {
+                assert!(core::ptr::eq(publish.data, &buf[7..]));
+                assert_eq!(publish.data, b"payload");
+            }
+            _ => panic!("expected a PUBLISH, got {:?}", parsed),
+        }
+        assert_eq!(Message::try_from(&parsed), Ok(msg));
+
+        let mut buf = [0u8; 64];
+        let msg = Message::connect("client", 30, false, true);
+        let len = msg.encode(&mut buf).unwrap();
+        let parsed: MessageRef = buf[..len].read(&mut 0).unwrap();
+        match parsed {
+            MessageRef::Connect(connect) => {
+                assert!(core::ptr::eq(connect.client_id.as_bytes(), &buf[6..len]));
+            }
+            _ => panic!("expected a CONNECT, got {:?}", parsed),
+        }
+        assert_eq!(Message::try_from(&parsed), Ok(msg));
+    }
+
+    #[test]
+    fn message_ref_matches_owned_parse() {
+        let msgs = [
+            Message::search_gw(1),
+            Message::gw_info(1),
+            Message::connect("client", 30, true, true),
+            Message::conn_ack(ReturnCode::Accepted),
+            Message::will_topic("will", QoS::AtLeastOnce, true),
+            Message::WillTopic(WillTopic::empty()),
+            Message::register(1, 2, "a/b"),
+            Message::reg_ack(1, 2, ReturnCode::Accepted),
+            Message::publish(Flags::default(), 1, 2, "data"),
+            Message::pub_ack(1, 2, ReturnCode::Accepted),
+            Message::subscribe(Flags::default(), 1, TopicNameOrId::Name("a/#".into())),
+            Message::sub_ack(Flags::default(), 1, 2, ReturnCode::Accepted),
+            Message::unsubscribe(Flags::from_bits(0x01), 1, TopicNameOrId::Id(2)),
+            Message::unsub_ack(1, ReturnCode::Accepted),
+            Message::ping_req(Some("client")),
+            Message::ping_req(None),
+            Message::ping_resp(),
+            Message::disconnect(Some(60)),
+            Message::Unknown(Unknown::from_parts(0x03, &[1, 2, 3]).unwrap()),
+        ];
+        let mut buf = [0u8; 300];
+        for msg in &msgs {
+            let len = msg.encode(&mut buf).unwrap();
+            let (parsed, parsed_len) = MessageRef::try_read(&buf[..len], ()).unwrap();
+            assert_eq!(parsed_len, len);
+            assert_eq!(Message::try_from(&parsed).as_ref(), Ok(msg));
+        }
+
+        // Over-capacity fields parse borrowed but don't convert, which is
+        // where the owned parse rejects them too.
+        let mut long = [b'a'; 80];
+        long[..2].copy_from_slice(&[80, 0x16]);
+        assert_eq!(
+            long.read::<Message>(&mut 0),
+            Err(byte::Error::BadInput {
+                err: "client_id longer than 64 bytes",
+            })
+        );
+        let parsed: MessageRef = long.read(&mut 0).unwrap();
+        assert_eq!(
+            parsed,
+            MessageRef::PingReq(PingReqRef {
+                client_id: Some(core::str::from_utf8(&long[2..]).unwrap())
+            })
+        );
+        assert_eq!(Message::try_from(&parsed), long.read::<Message>(&mut 0));
+    }
+}
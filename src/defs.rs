@@ -2,789 +2,1265 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use core::cmp::Ordering;
+use core::fmt;
+use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
 
 use bitfield::{bitfield_bitrange, bitfield_fields};
 use byte::{check_len, BytesExt, TryRead, TryWrite};
 use heapless::String;
 
+use crate::borrowed::{
+    ConnAckRef, ConnectRef, GwInfoRef, MessageRef, PingReqRef, PublishRef, RegAckRef, RegisterRef,
+    SubscribeRef, TopicNameOrIdRef, UnknownRef, UnsubscribeRef, WillTopicRef,
+};
+
 pub trait MsgType {
     const MSG_TYPE: u8;
 }
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Implements `TryWrite` for `T` by delegating to the impl on `&T`.
+macro_rules! impl_try_write_by_value {
+    (<const $n:ident: usize> $ty:ty) => {
+        impl<const $n: usize> TryWrite for $ty {
+            fn try_write(self, bytes: &mut [u8], ctx: ()) -> byte::Result<usize> {
+                (&self).try_write(bytes, ctx)
+            }
+        }
+    };
+    ($ty:ty) => {
+        impl TryWrite for $ty {
+            fn try_write(self, bytes: &mut [u8], ctx: ()) -> byte::Result<usize> {
+                (&self).try_write(bytes, ctx)
+            }
+        }
+    };
+}
+
+/// Implements `TryRead` by parsing the borrowed form from
+/// [`crate::borrowed`] and converting it.
+macro_rules! impl_try_read_via_ref {
+    (<const $n:ident: usize> $ty:ty, $ref:ident) => {
+        impl<const $n: usize> TryRead<'_> for $ty {
+            fn try_read(bytes: &[u8], ctx: ()) -> byte::Result<(Self, usize)> {
+                let (msg, len) = $ref::try_read(bytes, ctx)?;
+                Ok((msg.try_into()?, len))
+            }
+        }
+    };
+    ($ty:ty, $ref:ident) => {
+        impl TryRead<'_> for $ty {
+            fn try_read(bytes: &[u8], ctx: ()) -> byte::Result<(Self, usize)> {
+                let (msg, len) = $ref::try_read(bytes, ctx)?;
+                Ok((msg.try_into()?, len))
+            }
+        }
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Flags(u8);
-bitfield_bitrange! {struct Flags(u8)}
+pub enum DecodeError {
+    Incomplete,
+    BadOffset(usize),
+    BadInput(&'static str),
+    ZeroMsgId,
+    UnknownMessageType(u8),
+    InvalidFlags(u8),
+    DurationTooLong {
+        duration: u16,
+        max: u16,
+    },
+    InvalidTopicFilter,
+    InvalidTopicId,
+    BadLength {
+        declared: usize,
+        actual: usize,
+    },
+    InvalidClientId,
+    InvalidTopicIdType(u8),
+    UnknownReturnCode(u8),
+    /// [`WireFormat::decode`] on one message type found another.
+    UnexpectedMessageType(u8),
+}
 
-impl Flags {
-    bitfield_fields! {
-      u8;
-      pub dup, set_dup: 7;
-      pub qos, set_qos: 6, 5;
-      pub retain, set_retain: 4;
-      pub will, set_will: 3;
-      pub clean_session, set_clean_session: 2;
-      pub topic_id_type, set_topic_id_type: 1, 0;
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete => f.write_str("incomplete message"),
+            DecodeError::BadOffset(offset) => write!(f, "bad offset {}", offset),
+            DecodeError::BadInput(err) => f.write_str(err),
+            DecodeError::ZeroMsgId => f.write_str("msg_id must be nonzero"),
+            DecodeError::UnknownMessageType(t) => write!(f, "unknown message type 0x{:02x}", t),
+            DecodeError::InvalidFlags(flags) => write!(f, "invalid flags 0x{:02x}", flags),
+            DecodeError::DurationTooLong { duration, max } => {
+                write!(f, "duration {}s exceeds maximum of {}s", duration, max)
+            }
+            DecodeError::InvalidTopicFilter => f.write_str("invalid topic filter"),
+            DecodeError::InvalidTopicId => f.write_str("invalid topic id"),
+            DecodeError::BadLength { declared, actual } => write!(
+                f,
+                "length field is {} bytes but message is {} bytes",
+                declared, actual
+            ),
+            DecodeError::InvalidClientId => f.write_str("invalid client id"),
+            DecodeError::InvalidTopicIdType(t) => write!(f, "invalid topic id type {}", t),
+            DecodeError::UnknownReturnCode(code) => {
+                write!(f, "unknown return code 0x{:02x}", code)
+            }
+            DecodeError::UnexpectedMessageType(t) => {
+                write!(f, "unexpected message type 0x{:02x}", t)
+            }
+        }
     }
 }
 
-impl TryWrite for Flags {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, self.0)?;
-        Ok(*offset)
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl From<byte::Error> for DecodeError {
+    fn from(err: byte::Error) -> Self {
+        match err {
+            byte::Error::Incomplete => DecodeError::Incomplete,
+            byte::Error::BadOffset(offset) => DecodeError::BadOffset(offset),
+            byte::Error::BadInput { err } => DecodeError::BadInput(err),
+        }
     }
 }
 
-impl TryRead<'_> for Flags {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        Ok((Flags(bytes.read::<u8>(&mut 0)?), 1))
+impl From<DecodeError> for byte::Error {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::Incomplete => byte::Error::Incomplete,
+            DecodeError::BadOffset(offset) => byte::Error::BadOffset(offset),
+            DecodeError::BadInput(err) => byte::Error::BadInput { err },
+            DecodeError::ZeroMsgId => byte::Error::BadInput {
+                err: "msg_id must be nonzero",
+            },
+            DecodeError::UnknownMessageType(_) => byte::Error::BadInput {
+                err: "Received a message with unknown type",
+            },
+            DecodeError::InvalidFlags(_) => byte::Error::BadInput {
+                err: "Invalid flags for message type",
+            },
+            DecodeError::DurationTooLong { .. } => byte::Error::BadInput {
+                err: "Duration exceeds maximum",
+            },
+            DecodeError::InvalidTopicFilter => byte::Error::BadInput {
+                err: "Invalid topic filter",
+            },
+            DecodeError::InvalidTopicId => byte::Error::BadInput {
+                err: "Invalid topic id",
+            },
+            DecodeError::BadLength { .. } => byte::Error::BadInput {
+                err: "Length field doesn't match message length",
+            },
+            DecodeError::InvalidClientId => byte::Error::BadInput {
+                err: "Invalid client id",
+            },
+            DecodeError::InvalidTopicIdType(_) => byte::Error::BadInput {
+                err: "Reserved topic id type",
+            },
+            DecodeError::UnknownReturnCode(_) => byte::Error::BadInput {
+                err: "Unknown return code",
+            },
+            DecodeError::UnexpectedMessageType(_) => byte::Error::BadInput {
+                err: "Unexpected message type",
+            },
+        }
     }
 }
 
+/// Error returned by [`Message::encode`].
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum ReturnCode {
-    Accepted,
-    Rejected(RejectedReason),
+pub enum EncodeError {
+    BufferTooSmall {
+        needed: usize,
+        available: usize,
+    },
+    /// The message can't be encoded, e.g. a field is too long for its
+    /// length field.
+    BadInput(&'static str),
 }
 
-impl From<RejectedReason> for ReturnCode {
-    fn from(reason: RejectedReason) -> Self {
-        Self::Rejected(reason)
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall { needed, available } => write!(
+                f,
+                "message needs {} bytes but buffer has {}",
+                needed, available
+            ),
+            EncodeError::BadInput(err) => f.write_str(err),
+        }
     }
 }
 
-impl TryWrite for ReturnCode {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(
-            offset,
-            match self {
-                ReturnCode::Accepted => 0u8,
-                ReturnCode::Rejected(RejectedReason::Congestion) => 1u8,
-                ReturnCode::Rejected(RejectedReason::InvalidTopicId) => 2u8,
-                ReturnCode::Rejected(RejectedReason::NotSupported) => 3u8,
-                ReturnCode::Rejected(RejectedReason::Reserved(n)) => n,
-            },
-        )?;
-        Ok(*offset)
+#[cfg(feature = "std")]
+impl std::error::Error for EncodeError {}
+
+impl From<EncodeError> for byte::Error {
+    fn from(err: EncodeError) -> Self {
+        match err {
+            EncodeError::BufferTooSmall { .. } => byte::Error::Incomplete,
+            EncodeError::BadInput(err) => byte::Error::BadInput { err },
+        }
     }
 }
 
-impl TryRead<'_> for ReturnCode {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        Ok((
-            match bytes.read::<u8>(offset)? {
-                0 => ReturnCode::Accepted,
-                1 => ReturnCode::Rejected(RejectedReason::Congestion),
-                2 => ReturnCode::Rejected(RejectedReason::InvalidTopicId),
-                3 => ReturnCode::Rejected(RejectedReason::NotSupported),
-                n => ReturnCode::Rejected(RejectedReason::Reserved(n)),
-            },
-            *offset,
-        ))
+/// Coarse category of a decode failure, for counting malformed frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeErrorKind {
+    /// The buffer ends before the length field or the declared length.
+    TooShort,
+    /// The length field is too small to hold a message header, or below
+    /// [`min_len`] for the message type.
+    BadLength,
+    UnknownType(u8),
+    /// The framing is fine but a field didn't fit its type, e.g. a name
+    /// over capacity or invalid UTF-8.
+    FieldOverflow,
+}
+
+/// Categorizes why `bytes` failed to parse by inspecting only its framing.
+/// Meant to be called after a parse error; a valid buffer is reported as
+/// `FieldOverflow`.
+pub fn classify_error(bytes: &[u8]) -> DecodeErrorKind {
+    let offset = &mut 0;
+    let len = match read_raw_len(bytes, offset) {
+        Ok(len) => len,
+        Err(_) => return DecodeErrorKind::TooShort,
+    };
+    if len < *offset + 1 {
+        return DecodeErrorKind::BadLength;
+    }
+    if bytes.len() < len {
+        return DecodeErrorKind::TooShort;
     }
+    let msg_type = match bytes[*offset] {
+        t @ (0x01 | 0x02 | 0x04 | 0x05 | 0x07 | 0x0a..=0x0d | 0x12..=0x18 | 0xfe) => t,
+        t if is_reserved_type(t) => t,
+        t => return DecodeErrorKind::UnknownType(t),
+    };
+    // Same check as the parser; the 3-byte length field adds 2 bytes.
+    if len < min_len(msg_type) + *offset - 1 {
+        return DecodeErrorKind::BadLength;
+    }
+    DecodeErrorKind::FieldOverflow
 }
 
+/// Error returned by [`decode_needed`].
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum RejectedReason {
-    Congestion,
-    InvalidTopicId,
-    NotSupported,
-    Reserved(u8),
+pub enum Needed {
+    /// The buffer holds a valid prefix; this many more bytes are needed.
+    NeedMoreBytes(usize),
+    Error(DecodeError),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum MaybeForwardedMessage {
-    ForwardedMessage(ForwardedMessage),
-    Message(Message),
+/// Decodes the message at the start of `bytes`, returning it along with the
+/// number of bytes consumed. If the buffer ends early, reports how many more
+/// bytes are needed, going by the length field once that has arrived.
+pub fn decode_needed(bytes: &[u8]) -> Result<(Message, usize), Needed> {
+    let header = match bytes.first() {
+        None => return Err(Needed::NeedMoreBytes(1)),
+        Some(0x01) => 3,
+        Some(_) => 1,
+    };
+    if bytes.len() < header {
+        return Err(Needed::NeedMoreBytes(header - bytes.len()));
+    }
+    let len = read_len(bytes, &mut 0).map_err(|e| Needed::Error(e.into()))?;
+    if bytes.len() < len {
+        return Err(Needed::NeedMoreBytes(len - bytes.len()));
+    }
+    let offset = &mut 0;
+    let msg = bytes[..len]
+        .read(offset)
+        .map_err(|e: byte::Error| Needed::Error(e.into()))?;
+    Ok((msg, *offset))
 }
 
-impl From<ForwardedMessage> for MaybeForwardedMessage {
-    fn from(msg: ForwardedMessage) -> Self {
-        Self::ForwardedMessage(msg)
-    }
+/// Encodes `msg` at the start of `buf`, returning the number of bytes
+/// written. Same as [`Message::encode`].
+pub fn encode(msg: &Message, buf: &mut [u8]) -> Result<usize, EncodeError> {
+    msg.encode(buf)
 }
 
-impl<M: Into<Message>> From<M> for MaybeForwardedMessage {
-    fn from(msg: M) -> Self {
-        Self::Message(msg.into())
-    }
+/// Decodes the message at the start of `buf`, returning it and the number
+/// of bytes it took. Trailing bytes are left for the caller.
+pub fn decode(buf: &[u8]) -> Result<(Message, usize), DecodeError> {
+    decode_with(buf)
 }
 
-impl TryWrite for MaybeForwardedMessage {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        match self {
-            MaybeForwardedMessage::ForwardedMessage(msg) => bytes.write(offset, msg),
-            MaybeForwardedMessage::Message(msg) => bytes.write(offset, msg),
-        }?;
-        Ok(*offset)
+/// A message that can be measured, encoded and decoded on its own, so
+/// senders and receivers can be written once for any message type.
+pub trait WireFormat: Sized {
+    /// Number of bytes this message takes when encoded.
+    fn encoded_len(&self) -> usize;
+
+    /// Encodes at the start of `buf`, returning the number of bytes written.
+    /// Nothing is written if `buf` is too small.
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, EncodeError>;
+
+    /// Decodes from the start of `buf`, returning the message and the
+    /// number of bytes it took.
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError>;
+
+    /// Checks the constraints that strict parsing enforces on top of the
+    /// wire format. Nothing is checked by default.
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        Ok(())
     }
-}
 
-impl TryRead<'_> for MaybeForwardedMessage {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        check_len(bytes, 2)?;
-        let msg_type: u8 = bytes.read(&mut 1usize)?;
-        if msg_type == 0xfe {
-            let fw_msg: ForwardedMessage = bytes.read(offset)?;
-            Ok((fw_msg.into(), *offset))
-        } else {
-            let msg: Message = bytes.read(offset)?;
-            Ok((msg.into(), *offset))
+    /// Decodes like [`WireFormat::decode`], applying the checks selected by
+    /// `ctx`.
+    fn decode_ctx(buf: &[u8], ctx: ParseCtx) -> Result<(Self, usize), DecodeError> {
+        let (msg, len) = Self::decode(buf)?;
+        if ctx.strict {
+            msg.validate_with(ctx)?;
+            // Fixed-size fields can leave bytes of the declared length unread.
+            let (_msg_type, declared) = parse_header(buf)?;
+            if len != declared {
+                return Err(DecodeError::BadLength {
+                    declared,
+                    actual: len,
+                });
+            }
         }
+        Ok((msg, len))
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct ForwardedMessage {
-    pub ctrl: u8,
-    pub wireless_node_id: WirelessNodeId,
-    pub message: Message,
-}
+impl WireFormat for Message {
+    fn encoded_len(&self) -> usize {
+        Message::encoded_len(self)
+    }
 
-impl TryWrite for ForwardedMessage {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, 3 + self.wireless_node_id.len() as u8)?; // len
-        bytes.write(offset, 0xFEu8)?; // msg type
-        bytes.write(offset, self.ctrl)?;
-        bytes.write(offset, self.wireless_node_id.as_str())?;
-        bytes.write(offset, self.message)?;
-        Ok(*offset)
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        Message::encode(self, buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        decode(buf)
+    }
+
+    fn validate_with(&self, ctx: ParseCtx) -> Result<(), DecodeError> {
+        match self {
+            Message::SearchGw(msg) => msg.validate_with(ctx),
+            Message::GwInfo(msg) => msg.validate_with(ctx),
+            Message::Connect(msg) => msg.validate_with(ctx),
+            Message::ConnAck(msg) => msg.validate_with(ctx),
+            Message::WillTopic(msg) => msg.validate_with(ctx),
+            Message::Register(msg) => msg.validate_with(ctx),
+            Message::RegAck(msg) => msg.validate_with(ctx),
+            Message::Publish(msg) => msg.validate_with(ctx),
+            Message::PubAck(msg) => msg.validate_with(ctx),
+            Message::Subscribe(msg) => msg.validate_with(ctx),
+            Message::SubAck(msg) => msg.validate_with(ctx),
+            Message::Unsubscribe(msg) => msg.validate_with(ctx),
+            Message::UnsubAck(msg) => msg.validate_with(ctx),
+            Message::PingReq(msg) => msg.validate_with(ctx),
+            Message::PingResp(msg) => msg.validate_with(ctx),
+            Message::Disconnect(msg) => msg.validate_with(ctx),
+            Message::Unknown(msg) => msg.validate_with(ctx),
+        }
     }
 }
 
-impl TryRead<'_> for ForwardedMessage {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        bytes.read::<u8>(offset)?; // msg type
-        Ok((
-            ForwardedMessage {
-                ctrl: bytes.read(offset)?,
-                wireless_node_id: bytes.read_with(offset, len as usize - 3)?,
-                message: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+fn encode_checked<T>(msg: &T, needed: usize, buf: &mut [u8]) -> Result<usize, EncodeError>
+where
+    for<'a> &'a T: TryWrite,
+{
+    let too_small = EncodeError::BufferTooSmall {
+        needed,
+        available: buf.len(),
+    };
+    if buf.len() < needed {
+        return Err(too_small);
     }
+    let offset = &mut 0;
+    buf.write(offset, msg).map_err(|err| match err {
+        byte::Error::BadInput { err } => EncodeError::BadInput(err),
+        _ => too_small,
+    })?;
+    Ok(*offset)
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct WirelessNodeId(heapless::String<16>);
+fn decode_with<'a, T: TryRead<'a>>(buf: &'a [u8]) -> Result<(T, usize), DecodeError> {
+    let offset = &mut 0;
+    let msg = buf.read(offset)?;
+    Ok((msg, *offset))
+}
 
-impl WirelessNodeId {
-    pub fn new() -> Self {
-        Self(String::new())
+/// Like [`decode_with`], but fails if `buf` holds a message of another type.
+fn decode_msg<'a, T: TryRead<'a> + MsgType>(buf: &'a [u8]) -> Result<(T, usize), DecodeError> {
+    let msg_type = read_header(buf)?;
+    if msg_type != T::MSG_TYPE {
+        return Err(DecodeError::UnexpectedMessageType(msg_type));
     }
+    decode_with(buf)
 }
 
-impl From<&str> for WirelessNodeId {
-    fn from(s: &str) -> Self {
-        Self(String::from(s))
-    }
+/// Whether `msg_type` is reserved by the spec, so parsed as an [`Unknown`].
+pub(crate) fn is_reserved_type(msg_type: u8) -> bool {
+    matches!(msg_type, 0x03 | 0x11 | 0x19 | 0x1e..=0xfd)
 }
 
-impl Deref for WirelessNodeId {
-    type Target = heapless::String<16>;
+/// Implements [`WireFormat::encode`] and [`WireFormat::decode`] on top of a
+/// type's `TryWrite` and `TryRead` impls. With `encode`, only the former.
+macro_rules! wire_format_codec {
+    () => {
+        wire_format_codec!(encode);
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+        fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+            decode_msg(buf)
+        }
+    };
+    (encode) => {
+        fn encode(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+            encode_checked(self, self.encoded_len(), buf)
+        }
+    };
 }
 
-impl DerefMut for WirelessNodeId {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+/// msg_id 0 is reserved in messages that need an id.
+fn check_msg_id(msg_id: u16) -> Result<(), DecodeError> {
+    if msg_id == 0 {
+        return Err(DecodeError::ZeroMsgId);
     }
+    Ok(())
 }
 
-impl TryWrite for WirelessNodeId {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, self.as_str())?;
-        Ok(*offset)
+fn check_topic_id_type(flags: Flags) -> Result<(), DecodeError> {
+    if TopicIdType::from(flags.topic_id_type()) == TopicIdType::Reserved {
+        return Err(DecodeError::InvalidTopicIdType(flags.topic_id_type()));
     }
+    Ok(())
 }
 
-impl TryRead<'_, usize> for WirelessNodeId {
-    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let mut s = String::new();
-        s.push_str(bytes.read_with(offset, byte::ctx::Str::Len(len))?)
-            .map_err(|_e| byte::Error::BadInput {
-                err: "wireless_node_id longer than 16 bytes",
-            })?;
-        Ok((WirelessNodeId(s), *offset))
+fn check_return_code(code: ReturnCode) -> Result<(), DecodeError> {
+    if let ReturnCode::Rejected(RejectedReason::Reserved(n)) = code {
+        return Err(DecodeError::UnknownReturnCode(n));
     }
+    Ok(())
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum Message {
-    SearchGw(SearchGw),
-    GwInfo(GwInfo),
-    Connect(Connect),
-    ConnAck(ConnAck),
-    Register(Register),
-    RegAck(RegAck),
-    Publish(Publish),
-    PubAck(PubAck),
-    Subscribe(Subscribe),
-    SubAck(SubAck),
-    Unsubscribe(Unsubscribe),
-    UnsubAck(UnsubAck),
-    PingReq(PingReq),
-    PingResp(PingResp),
+/// Rejects extension bytes after a fixed-length ack unless they are complete
+/// TLVs. Anything else is reported as a length mismatch, as if the length
+/// field overstated the message.
+fn check_extension(fixed_len: usize, extra: &[u8]) -> Result<(), DecodeError> {
+    let mut tlvs = TlvIter::new(extra);
+    tlvs.by_ref().for_each(drop);
+    if !tlvs.remainder().is_empty() {
+        return Err(DecodeError::BadLength {
+            declared: fixed_len + extra.len(),
+            actual: fixed_len + extra.len() - tlvs.remainder().len(),
+        });
+    }
+    Ok(())
 }
 
-impl From<SearchGw> for Message {
-    fn from(msg: SearchGw) -> Self {
-        Message::SearchGw(msg)
+fn check_client_id<const N: usize>(
+    client_id: &ClientId<N>,
+    ctx: ParseCtx,
+) -> Result<(), DecodeError> {
+    match ctx.max_client_id_len {
+        Some(max) if !client_id.is_valid_with_max(max) => Err(DecodeError::InvalidClientId),
+        _ => Ok(()),
     }
 }
 
-impl From<GwInfo> for Message {
-    fn from(msg: GwInfo) -> Self {
-        Message::GwInfo(msg)
-    }
+/// Parses a buffer that must hold exactly one message, such as a datagram
+/// from a client that isn't behind a forwarder. Trailing bytes are reported
+/// as [`DecodeError::BadLength`].
+pub fn parse_exact(bytes: &[u8]) -> Result<Message, DecodeError> {
+    parse_exact_with(bytes, ParseCtx::default())
 }
 
-impl From<Connect> for Message {
-    fn from(msg: Connect) -> Self {
-        Message::Connect(msg)
+/// Like [`parse_exact`], applying the checks selected by `ctx`.
+pub fn parse_exact_with(bytes: &[u8], ctx: ParseCtx) -> Result<Message, DecodeError> {
+    let (msg, len) = Message::decode_ctx(bytes, ctx)?;
+    if len != bytes.len() {
+        return Err(DecodeError::BadLength {
+            declared: len,
+            actual: bytes.len(),
+        });
     }
+    Ok(msg)
 }
 
-impl From<ConnAck> for Message {
-    fn from(msg: ConnAck) -> Self {
-        Message::ConnAck(msg)
+/// Encodes `msg`, decodes it back and checks that the result is equal and
+/// consumed exactly the encoded bytes.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn roundtrip(msg: &Message) -> bool {
+    let mut bytes = [0u8; SCRATCH_LEN];
+    let len = &mut 0;
+    if bytes.write(len, msg).is_err() {
+        return false;
+    }
+    let offset = &mut 0;
+    match bytes[..*len].read::<Message>(offset) {
+        Ok(decoded) => decoded == *msg && *offset == *len,
+        Err(_) => false,
     }
 }
 
-impl From<Register> for Message {
-    fn from(msg: Register) -> Self {
-        Message::Register(msg)
+/// Describes the first field that differs between `a` and `b`, or returns
+/// `None` if they are equal. Payloads are reported by the first differing
+/// byte rather than printed whole.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn diff(a: &Message, b: &Message) -> Option<String<128>> {
+    match (a, b) {
+        (Message::SearchGw(a), Message::SearchGw(b)) => diff_field("radius", &a.radius, &b.radius),
+        (Message::GwInfo(a), Message::GwInfo(b)) => diff_field("gw_id", &a.gw_id, &b.gw_id)
+            .or_else(|| diff_field("gw_add", &a.gw_add, &b.gw_add)),
+        (Message::Connect(a), Message::Connect(b)) => diff_field("flags", &a.flags, &b.flags)
+            .or_else(|| diff_field("duration", &a.duration, &b.duration))
+            .or_else(|| diff_field("client_id", &a.client_id, &b.client_id)),
+        (Message::ConnAck(a), Message::ConnAck(b)) => {
+            diff_field("code", &a.code, &b.code).or_else(|| diff_bytes("extra", &a.extra, &b.extra))
+        }
+        (Message::WillTopic(a), Message::WillTopic(b)) => diff_field("flags", &a.flags, &b.flags)
+            .or_else(|| diff_field("topic_name", &a.topic_name, &b.topic_name)),
+        (Message::Register(a), Message::Register(b)) => {
+            diff_field("topic_id", &a.topic_id, &b.topic_id)
+                .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+                .or_else(|| diff_field("topic_name", &a.topic_name, &b.topic_name))
+        }
+        (Message::RegAck(a), Message::RegAck(b)) => {
+            diff_field("topic_id", &a.topic_id, &b.topic_id)
+                .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+                .or_else(|| diff_field("code", &a.code, &b.code))
+                .or_else(|| diff_bytes("extra", &a.extra, &b.extra))
+        }
+        (Message::Publish(a), Message::Publish(b)) => diff_field("flags", &a.flags, &b.flags)
+            .or_else(|| diff_field("topic_id", &a.topic_id, &b.topic_id))
+            .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+            .or_else(|| diff_bytes("data", &a.data, &b.data)),
+        (Message::PubAck(a), Message::PubAck(b)) => {
+            diff_field("topic_id", &a.topic_id, &b.topic_id)
+                .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+                .or_else(|| diff_field("code", &a.code, &b.code))
+        }
+        (Message::Subscribe(a), Message::Subscribe(b)) => diff_field("flags", &a.flags, &b.flags)
+            .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+            .or_else(|| diff_field("topic", &a.topic, &b.topic)),
+        (Message::SubAck(a), Message::SubAck(b)) => diff_field("flags", &a.flags, &b.flags)
+            .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+            .or_else(|| diff_field("topic_id", &a.topic_id, &b.topic_id))
+            .or_else(|| diff_field("code", &a.code, &b.code)),
+        (Message::Unsubscribe(a), Message::Unsubscribe(b)) => {
+            diff_field("flags", &a.flags, &b.flags)
+                .or_else(|| diff_field("msg_id", &a.msg_id, &b.msg_id))
+                .or_else(|| diff_field("topic", &a.topic, &b.topic))
+        }
+        (Message::UnsubAck(a), Message::UnsubAck(b)) => diff_field("msg_id", &a.msg_id, &b.msg_id)
+            .or_else(|| diff_field("code", &a.code, &b.code)),
+        (Message::PingReq(a), Message::PingReq(b)) => {
+            diff_field("client_id", &a.client_id, &b.client_id)
+        }
+        (Message::PingResp(_), Message::PingResp(_)) => None,
+        (Message::Disconnect(a), Message::Disconnect(b)) => {
+            diff_field("duration", &a.duration, &b.duration)
+        }
+        (Message::Unknown(a), Message::Unknown(b)) => {
+            diff_field("msg_type", &a.msg_type, &b.msg_type)
+                .or_else(|| diff_bytes("body", &a.body, &b.body))
+        }
+        _ => diff_field("type", &a.kind_name(), &b.kind_name()),
     }
 }
 
-impl From<RegAck> for Message {
-    fn from(msg: RegAck) -> Self {
-        Message::RegAck(msg)
+/// Writes `name: a != b`, or just the name if that doesn't fit.
+#[cfg(any(test, feature = "test-utils"))]
+fn diff_field<T: PartialEq + fmt::Debug>(name: &str, a: &T, b: &T) -> Option<String<128>> {
+    use core::fmt::Write;
+    if a == b {
+        return None;
+    }
+    let mut out = String::new();
+    if write!(out, "{}: {:?} != {:?}", name, a, b).is_err() {
+        out.clear();
+        out.push_str(name).ok();
     }
+    Some(out)
 }
 
-impl From<Publish> for Message {
-    fn from(msg: Publish) -> Self {
-        Message::Publish(msg)
+#[cfg(any(test, feature = "test-utils"))]
+fn diff_bytes(name: &str, a: &[u8], b: &[u8]) -> Option<String<128>> {
+    use core::fmt::Write;
+    let mut out = String::new();
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(i) => write!(out, "{}[{}]: 0x{:02x} != 0x{:02x}", name, i, a[i], b[i]),
+        None if a.len() != b.len() => write!(out, "{}: len {} != {}", name, a.len(), b.len()),
+        None => return None,
     }
+    .ok();
+    Some(out)
 }
 
-impl From<PubAck> for Message {
-    fn from(msg: PubAck) -> Self {
-        Message::PubAck(msg)
+/// Reads a 1- or 3-byte length field, returning the total message length.
+/// Rejects lengths too short to cover the length field and type byte.
+pub(crate) fn read_len(bytes: &[u8], offset: &mut usize) -> byte::Result<usize> {
+    let len = read_raw_len(bytes, offset)?;
+    if len < *offset + 1 {
+        return Err(DecodeError::BadLength {
+            declared: len,
+            actual: bytes.len(),
+        }
+        .into());
     }
+    Ok(len)
 }
 
-impl From<Subscribe> for Message {
-    fn from(msg: Subscribe) -> Self {
-        Message::Subscribe(msg)
+/// Reads the length field without checking it against the header size.
+fn read_raw_len(bytes: &[u8], offset: &mut usize) -> byte::Result<usize> {
+    match bytes.read::<u8>(offset)? {
+        0x01 => Ok(bytes.read_with::<u16>(offset, byte::ctx::BE)? as usize),
+        len => Ok(len as usize),
     }
 }
 
-impl From<SubAck> for Message {
-    fn from(msg: SubAck) -> Self {
-        Message::SubAck(msg)
-    }
+/// The fields of a message that follow its type byte. The framed encoding
+/// and [`Message::write_body`] / [`Message::parse_body`] share these.
+trait MessageBody: Sized {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()>;
+
+    /// Reads the fields starting at `offset`. `bytes` ends where the message
+    /// does, so a trailing variable-length field takes what's left.
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self>;
 }
 
-impl From<Unsubscribe> for Message {
-    fn from(msg: Unsubscribe) -> Self {
-        Message::Unsubscribe(msg)
+/// Writes the length field for a message with `len` bytes following it,
+/// using the 3-byte form when the total doesn't fit in one byte.
+fn write_len(bytes: &mut [u8], offset: &mut usize, len: usize) -> byte::Result<()> {
+    if len < 0xff {
+        bytes.write(offset, (len + 1) as u8)
+    } else if len + 3 <= 0xffff {
+        bytes.write(offset, 0x01u8)?;
+        bytes.write_with(offset, (len + 3) as u16, byte::ctx::BE)
+    } else {
+        Err(byte::Error::BadInput {
+            err: "Message longer than 65535 bytes",
+        })
     }
 }
 
-impl From<UnsubAck> for Message {
-    fn from(msg: UnsubAck) -> Self {
-        Message::UnsubAck(msg)
+/// Smallest valid length of a message of type `msg_type` with a 1-byte
+/// length field, counting the length field itself.
+pub fn min_len(msg_type: u8) -> usize {
+    match msg_type {
+        0x01 | 0x02 | 0x05 => 3, // SEARCHGW, GWINFO, CONNACK
+        0x04 | 0x0a => 6,        // CONNECT, REGISTER
+        0x0b..=0x0d => 7,        // REGACK, PUBLISH, PUBACK
+        0x12 | 0x14 => 5,        // SUBSCRIBE, UNSUBSCRIBE
+        0x13 => 8,               // SUBACK
+        0x15 => 5,               // UNSUBACK
+        0xfe => 3,               // forwarder encapsulation
+        _ => 2,                  // WILLTOPIC, PINGREQ, PINGRESP, DISCONNECT, unknown
     }
 }
 
-impl From<PingReq> for Message {
-    fn from(msg: PingReq) -> Self {
-        Message::PingReq(msg)
+/// Reads the length field and type byte shared by every message, rejecting a
+/// length below [`min_len`] for the type. Doesn't advance past the header.
+pub(crate) fn read_header(bytes: &[u8]) -> byte::Result<u8> {
+    let header = parse_header(bytes);
+    #[cfg(feature = "log")]
+    match &header {
+        Ok((msg_type, len)) => log::trace!("header: type 0x{:02x}, len {}", msg_type, len),
+        Err(err) => log::debug!("bad header: {:?}", err),
     }
+    header.map(|(msg_type, _len)| msg_type)
 }
 
-impl From<PingResp> for Message {
-    fn from(msg: PingResp) -> Self {
-        Message::PingResp(msg)
+/// Returns the type byte and total length of the message in `bytes`.
+fn parse_header(bytes: &[u8]) -> byte::Result<(u8, usize)> {
+    let offset = &mut 0;
+    let len = read_len(bytes, offset)?;
+    let msg_type: u8 = bytes.read(offset)?;
+    // The 3-byte length field adds 2 bytes to every minimum.
+    if len < min_len(msg_type) + *offset - 2 {
+        return Err(DecodeError::BadLength {
+            declared: len,
+            actual: bytes.len(),
+        }
+        .into());
     }
+    Ok((msg_type, len))
 }
 
-impl TryWrite for Message {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        match self {
-            Message::SearchGw(msg) => bytes.write(offset, msg),
-            Message::GwInfo(msg) => bytes.write(offset, msg),
-            Message::Connect(msg) => bytes.write(offset, msg),
-            Message::ConnAck(msg) => bytes.write(offset, msg),
-            Message::Register(msg) => bytes.write(offset, msg),
-            Message::RegAck(msg) => bytes.write(offset, msg),
-            Message::Publish(msg) => bytes.write(offset, msg),
-            Message::PubAck(msg) => bytes.write(offset, msg),
-            Message::Subscribe(msg) => bytes.write(offset, msg),
-            Message::SubAck(msg) => bytes.write(offset, msg),
-            Message::Unsubscribe(msg) => bytes.write(offset, msg),
-            Message::UnsubAck(msg) => bytes.write(offset, msg),
-            Message::PingReq(msg) => bytes.write(offset, msg),
-            Message::PingResp(msg) => bytes.write(offset, msg),
-        }?;
-        Ok(*offset)
+/// Total length of a message with `len` bytes after its length field, as
+/// framed by [`write_len`].
+fn len_with_header(len: usize) -> usize {
+    if len < 0xff {
+        len + 1
+    } else {
+        len + 3
     }
 }
 
-impl TryRead<'_> for Message {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        // Not increasing offset because some messages needs access to len.
-        Ok((
-            match bytes.read::<u8>(&mut (*offset + 1))? {
-                0x01 => Message::SearchGw(bytes.read(offset)?),
-                0x02 => Message::GwInfo(bytes.read(offset)?),
-                0x04 => Message::Connect(bytes.read(offset)?),
-                0x05 => Message::ConnAck(bytes.read(offset)?),
-                0x0a => Message::Register(bytes.read(offset)?),
-                0x0b => Message::RegAck(bytes.read(offset)?),
-                0x0c => Message::Publish(bytes.read(offset)?),
-                0x0d => Message::PubAck(bytes.read(offset)?),
-                Subscribe::MSG_TYPE => Message::Subscribe(bytes.read(offset)?),
-                SubAck::MSG_TYPE => Message::SubAck(bytes.read(offset)?),
-                Unsubscribe::MSG_TYPE => Message::Unsubscribe(bytes.read(offset)?),
-                UnsubAck::MSG_TYPE => Message::UnsubAck(bytes.read(offset)?),
-                0x16 => Message::PingReq(bytes.read(offset)?),
-                0x17 => Message::PingResp(bytes.read(offset)?),
-                _t => {
-                    return Err(byte::Error::BadInput {
-                        err: "Recieved a message with unknown type",
-                    })
-                }
-            },
-            *offset,
-        ))
-    }
-}
+/// Size of a scratch buffer that can hold any encoded message, including
+/// one with a 3-byte length field and full-size payload.
+pub(crate) const SCRATCH_LEN: usize = 512;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Context for parsing a [`Message`]. In strict mode, messages that are
+/// well-formed on the wire but violate the spec are rejected, as are
+/// messages whose length field doesn't match the bytes their fields take.
+/// Extension bytes after a CONNACK or REGACK count as fields only if they
+/// are complete TLVs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct SearchGw {
-    pub radius: u8,
+#[non_exhaustive]
+pub struct ParseCtx {
+    pub strict: bool,
+    /// In strict mode, also reject client ids that fail
+    /// [`ClientId::is_valid_with_max`] for this length.
+    pub max_client_id_len: Option<usize>,
 }
 
-impl TryWrite for SearchGw {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, 3u8)?; // len
-        bytes.write(offset, 0x01u8)?; // msg type
-        bytes.write(offset, self.radius)?;
-        Ok(*offset)
+impl ParseCtx {
+    /// Accepts anything well-formed on the wire; the same as `default()`.
+    pub const fn lenient() -> Self {
+        ParseCtx {
+            strict: false,
+            max_client_id_len: None,
+        }
     }
-}
 
-impl TryRead<'_> for SearchGw {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        *offset += 1; // msg type
-        Ok((
-            SearchGw {
-                radius: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+    pub const fn strict() -> Self {
+        ParseCtx {
+            strict: true,
+            max_client_id_len: None,
+        }
+    }
+
+    /// Sets [`ParseCtx::max_client_id_len`].
+    pub const fn with_max_client_id_len(mut self, max: usize) -> Self {
+        self.max_client_id_len = Some(max);
+        self
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct GwInfo {
-    pub gw_id: u8,
+pub struct Flags(u8);
+bitfield_bitrange! {struct Flags(u8)}
+
+impl Flags {
+    pub const DUP: u8 = 0b1000_0000;
+    pub const QOS_MASK: u8 = 0b0110_0000;
+    pub const RETAIN: u8 = 0b0001_0000;
+    pub const WILL: u8 = 0b0000_1000;
+    pub const CLEAN_SESSION: u8 = 0b0000_0100;
+    pub const TOPIC_ID_TYPE_MASK: u8 = 0b0000_0011;
+
+    /// The bits that mean something in each message type, for use with
+    /// [`Flags::semantic_eq`]. DUP is left out since it only marks a
+    /// retransmission.
+    pub const CONNECT_MASK: u8 = Self::WILL | Self::CLEAN_SESSION;
+    pub const WILL_TOPIC_MASK: u8 = Self::QOS_MASK | Self::RETAIN;
+    pub const PUBLISH_MASK: u8 = Self::QOS_MASK | Self::RETAIN | Self::TOPIC_ID_TYPE_MASK;
+    pub const SUBSCRIBE_MASK: u8 = Self::QOS_MASK | Self::TOPIC_ID_TYPE_MASK;
+    pub const SUB_ACK_MASK: u8 = Self::QOS_MASK;
+
+    /// Compares only the bits set in `mask`.
+    pub const fn semantic_eq(&self, other: &Flags, mask: u8) -> bool {
+        self.0 & mask == other.0 & mask
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Flags(bits)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    bitfield_fields! {
+      u8;
+      pub dup, set_dup: 7;
+      pub qos, set_qos: 6, 5;
+      pub retain, set_retain: 4;
+      pub will, set_will: 3;
+      pub clean_session, set_clean_session: 2;
+      pub topic_id_type, set_topic_id_type: 1, 0;
+    }
 }
 
-impl TryWrite for GwInfo {
+impl TryWrite for Flags {
     fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
         let offset = &mut 0;
-        bytes.write(offset, 3u8)?; // len
-        bytes.write(offset, 0x02u8)?; // msg type
-        bytes.write(offset, self.gw_id)?;
+        bytes.write(offset, self.0)?;
         Ok(*offset)
     }
 }
 
-impl TryRead<'_> for GwInfo {
+impl TryRead<'_> for Flags {
     fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        *offset += 1; // msg type
-        Ok((
-            GwInfo {
-                gw_id: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+        Ok((Flags(bytes.read::<u8>(&mut 0)?), 1))
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// Interpretation of the `topic_id_type` flag bits. `Normal` is a registered
+/// topic id, or a full topic name in SUBSCRIBE/UNSUBSCRIBE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Connect {
-    pub flags: Flags,
-    pub duration: u16,
-    pub client_id: ClientId,
+pub enum TopicIdType {
+    Normal,
+    Predefined,
+    ShortName,
+    Reserved,
 }
 
-impl TryWrite for Connect {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        let len = 6 + self.client_id.len() as u8;
-        bytes.write(offset, len)?;
-        bytes.write(offset, 0x04u8)?; // msg type
-        bytes.write(offset, self.flags)?;
-        bytes.write(offset, 0x01u8)?; // protocol id
-        bytes.write_with(offset, self.duration, byte::ctx::BE)?;
-        bytes.write(offset, self.client_id.as_str())?;
-        Ok(*offset)
+impl TopicIdType {
+    /// Every topic id type with a short name, in wire order.
+    pub fn all() -> &'static [(TopicIdType, &'static str)] {
+        &[
+            (TopicIdType::Normal, "normal"),
+            (TopicIdType::Predefined, "predefined"),
+            (TopicIdType::ShortName, "short name"),
+            (TopicIdType::Reserved, "reserved"),
+        ]
     }
 }
 
-impl TryRead<'_> for Connect {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        if len < 6 {
-            return Err(byte::Error::BadInput {
-                err: "Connect len must be >= 6 bytes",
-            });
+impl From<u8> for TopicIdType {
+    fn from(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => TopicIdType::Normal,
+            1 => TopicIdType::Predefined,
+            2 => TopicIdType::ShortName,
+            _ => TopicIdType::Reserved,
         }
-        *offset += 1; // msg type
-        let flags = bytes.read(offset)?;
-        bytes.read::<u8>(offset)?; // protocol id
-        Ok((
-            Connect {
-                flags,
-                duration: bytes.read_with(offset, byte::ctx::BE)?,
-                client_id: bytes.read_with(offset, len as usize - 6)?,
-            },
-            *offset,
-        ))
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+impl From<TopicIdType> for u8 {
+    fn from(topic_id_type: TopicIdType) -> Self {
+        match topic_id_type {
+            TopicIdType::Normal => 0,
+            TopicIdType::Predefined => 1,
+            TopicIdType::ShortName => 2,
+            TopicIdType::Reserved => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct ClientId(heapless::String<64>);
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    MinusOne,
+}
 
-impl ClientId {
-    pub fn new() -> Self {
-        Self(String::new())
+impl QoS {
+    /// Every QoS with a short name, in wire order.
+    pub fn all() -> &'static [(QoS, &'static str)] {
+        &[
+            (QoS::AtMostOnce, "at most once"),
+            (QoS::AtLeastOnce, "at least once"),
+            (QoS::ExactlyOnce, "exactly once"),
+            (QoS::MinusOne, "minus one"),
+        ]
     }
 }
 
-impl From<&str> for ClientId {
-    fn from(s: &str) -> Self {
-        Self(String::from(s))
+impl From<u8> for QoS {
+    fn from(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => QoS::MinusOne,
+        }
     }
 }
 
-impl Deref for ClientId {
-    type Target = heapless::String<64>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl From<QoS> for u8 {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+            QoS::MinusOne => 3,
+        }
     }
 }
 
-impl DerefMut for ClientId {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReturnCode {
+    Accepted,
+    Rejected(RejectedReason),
 }
 
-impl TryWrite for ClientId {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+impl ReturnCode {
+    pub fn code(&self) -> u8 {
+        match self {
+            ReturnCode::Accepted => 0u8,
+            ReturnCode::Rejected(RejectedReason::Congestion) => 1u8,
+            ReturnCode::Rejected(RejectedReason::InvalidTopicId) => 2u8,
+            ReturnCode::Rejected(RejectedReason::NotSupported) => 3u8,
+            ReturnCode::Rejected(RejectedReason::Reserved(n)) => *n,
+        }
+    }
+
+    pub fn from_u8(code: u8) -> Self {
+        match code {
+            0 => ReturnCode::Accepted,
+            1 => ReturnCode::Rejected(RejectedReason::Congestion),
+            2 => ReturnCode::Rejected(RejectedReason::InvalidTopicId),
+            3 => ReturnCode::Rejected(RejectedReason::NotSupported),
+            n => ReturnCode::Rejected(RejectedReason::Reserved(n)),
+        }
+    }
+
+    /// Maps to the closest MQTT v5 reason code:
+    ///
+    /// | Return code    | Reason code                        |
+    /// |----------------|------------------------------------|
+    /// | Accepted       | 0x00 Success                       |
+    /// | Congestion     | 0x97 Quota exceeded                |
+    /// | InvalidTopicId | 0x90 Topic Name invalid            |
+    /// | NotSupported   | 0x83 Implementation specific error |
+    /// | Reserved(_)    | 0x80 Unspecified error             |
+    pub fn to_mqtt_reason(&self) -> u8 {
+        match self {
+            ReturnCode::Accepted => 0x00,
+            ReturnCode::Rejected(RejectedReason::Congestion) => 0x97,
+            ReturnCode::Rejected(RejectedReason::InvalidTopicId) => 0x90,
+            ReturnCode::Rejected(RejectedReason::NotSupported) => 0x83,
+            ReturnCode::Rejected(RejectedReason::Reserved(_)) => 0x80,
+        }
+    }
+
+    /// Maps an MQTT v5 reason code back to a return code. Every success code
+    /// (below 0x80) is `Accepted`; 0x89 Server busy, 0x97 Quota exceeded and
+    /// 0x9f Connection rate exceeded are `Congestion`; 0x8f Topic Filter
+    /// invalid and 0x90 Topic Name invalid are `InvalidTopicId`; any other
+    /// failure is `NotSupported`.
+    pub fn from_mqtt_reason(reason: u8) -> Self {
+        match reason {
+            0x00..=0x7f => ReturnCode::Accepted,
+            0x89 | 0x97 | 0x9f => ReturnCode::Rejected(RejectedReason::Congestion),
+            0x8f | 0x90 => ReturnCode::Rejected(RejectedReason::InvalidTopicId),
+            _ => ReturnCode::Rejected(RejectedReason::NotSupported),
+        }
+    }
+
+    /// Reads a return code like `TryRead`. In strict mode, codes above 3 are
+    /// rejected rather than read as [`RejectedReason::Reserved`].
+    pub fn parse(bytes: &[u8], ctx: ParseCtx) -> byte::Result<(Self, usize)> {
         let offset = &mut 0;
-        bytes.write(offset, self.as_str())?;
-        Ok(*offset)
+        let code: ReturnCode = bytes.read(offset)?;
+        match code {
+            ReturnCode::Rejected(RejectedReason::Reserved(n)) if ctx.strict => {
+                Err(DecodeError::UnknownReturnCode(n).into())
+            }
+            _ => Ok((code, *offset)),
+        }
     }
 }
 
-impl TryRead<'_, usize> for ClientId {
-    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let mut s = String::new();
-        s.push_str(bytes.read_with(offset, byte::ctx::Str::Len(len))?)
-            .map_err(|_e| byte::Error::BadInput {
-                err: "client_id longer than 64 bytes",
-            })?;
-        Ok((ClientId(s), *offset))
+impl From<RejectedReason> for ReturnCode {
+    fn from(reason: RejectedReason) -> Self {
+        Self::Rejected(reason)
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct ConnAck {
-    pub code: ReturnCode,
+impl From<u8> for ReturnCode {
+    fn from(code: u8) -> Self {
+        Self::from_u8(code)
+    }
 }
 
-impl TryWrite for ConnAck {
+impl TryWrite for ReturnCode {
     fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
         let offset = &mut 0;
-        bytes.write(offset, 3u8)?; // len
-        bytes.write(offset, 0x05u8)?; // msg type
-        bytes.write(offset, self.code)?;
+        bytes.write(offset, self.code())?;
         Ok(*offset)
     }
 }
 
-impl TryRead<'_> for ConnAck {
+impl TryRead<'_> for ReturnCode {
     fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
         let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        *offset += 1; // msg type
-        Ok((
-            ConnAck {
-                code: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+        Ok((ReturnCode::from_u8(bytes.read::<u8>(offset)?), *offset))
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Register {
-    pub topic_id: u16,
-    pub msg_id: u16,
-    pub topic_name: TopicName,
+pub enum RejectedReason {
+    Congestion,
+    InvalidTopicId,
+    NotSupported,
+    Reserved(u8),
 }
 
-impl TryWrite for Register {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        let len = 6 + self.topic_name.len() as u8;
-        bytes.write(offset, len)?;
-        bytes.write(offset, 0x0Au8)?; // msg type
-        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.topic_name.as_str())?;
-        Ok(*offset)
+impl RejectedReason {
+    /// How long to wait before retrying after a congestion rejection. The
+    /// spec leaves this open; 10 seconds matches its suggested Tretry.
+    pub const DEFAULT_RETRY_AFTER_SECS: u16 = 10;
+
+    /// The backoff in seconds before retrying, following the common
+    /// convention of waiting Tretry on congestion. Other rejections won't
+    /// succeed on retry and give `None`.
+    pub fn suggested_backoff(&self) -> Option<u16> {
+        match self {
+            RejectedReason::Congestion => Some(Self::DEFAULT_RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+
+    /// The reasons defined by the spec, named as by `Display`. `Reserved`
+    /// codes are left out.
+    pub fn named() -> &'static [(RejectedReason, &'static str)] {
+        &[
+            (RejectedReason::Congestion, "congestion"),
+            (RejectedReason::InvalidTopicId, "invalid topic id"),
+            (RejectedReason::NotSupported, "not supported"),
+        ]
     }
 }
 
-impl TryRead<'_> for Register {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        if len < 6 {
-            return Err(byte::Error::BadInput {
-                err: "Register len must be >= 6 bytes",
-            });
+impl fmt::Display for RejectedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectedReason::Congestion => f.write_str("congestion"),
+            RejectedReason::InvalidTopicId => f.write_str("invalid topic id"),
+            RejectedReason::NotSupported => f.write_str("not supported"),
+            RejectedReason::Reserved(code) => write!(f, "reserved(0x{:02x})", code),
         }
-        *offset += 1; // msg type
-        Ok((
-            Register {
-                topic_id: bytes.read_with(offset, byte::ctx::BE)?,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                topic_name: bytes.read_with(offset, len as usize - 6)?,
-            },
-            *offset,
-        ))
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct TopicName(heapless::String<256>);
-
-impl TopicName {
-    pub fn from(s: &str) -> Self {
-        Self(String::from(s))
-    }
-    pub fn new() -> Self {
-        Self(String::new())
+impl fmt::Display for ReturnCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReturnCode::Accepted => f.write_str("accepted"),
+            ReturnCode::Rejected(reason) => write!(f, "rejected: {}", reason),
+        }
     }
 }
 
-impl Deref for TopicName {
-    type Target = heapless::String<256>;
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MaybeForwardedMessage {
+    ForwardedMessage(ForwardedMessage),
+    Message(Message),
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl MaybeForwardedMessage {
+    /// The message itself, whether or not it was forwarded.
+    pub fn inner(&self) -> &Message {
+        match self {
+            MaybeForwardedMessage::ForwardedMessage(msg) => &msg.message,
+            MaybeForwardedMessage::Message(msg) => msg,
+        }
+    }
+
+    /// The node a forwarded message came from.
+    pub fn wireless_node_id(&self) -> Option<&WirelessNodeId> {
+        match self {
+            MaybeForwardedMessage::ForwardedMessage(msg) => Some(&msg.wireless_node_id),
+            MaybeForwardedMessage::Message(_) => None,
+        }
     }
 }
 
-impl From<&str> for TopicName {
-    fn from(s: &str) -> Self {
-        Self(String::from(s))
+impl From<ForwardedMessage> for MaybeForwardedMessage {
+    fn from(msg: ForwardedMessage) -> Self {
+        Self::ForwardedMessage(msg)
     }
 }
 
-impl DerefMut for TopicName {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+impl<M: Into<Message>> From<M> for MaybeForwardedMessage {
+    fn from(msg: M) -> Self {
+        Self::Message(msg.into())
     }
 }
 
-impl TryWrite for TopicName {
+impl TryWrite for &MaybeForwardedMessage {
     fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
         let offset = &mut 0;
-        bytes.write(offset, self.as_str())?;
+        match self {
+            MaybeForwardedMessage::ForwardedMessage(msg) => bytes.write(offset, msg),
+            MaybeForwardedMessage::Message(msg) => bytes.write(offset, msg),
+        }?;
         Ok(*offset)
     }
 }
 
-impl TryRead<'_, usize> for TopicName {
-    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
+impl_try_write_by_value!(MaybeForwardedMessage);
+
+impl TryRead<'_> for MaybeForwardedMessage {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
         let offset = &mut 0;
-        let mut s = String::new();
-        s.push_str(bytes.read_with(offset, byte::ctx::Str::Len(len))?)
-            .map_err(|_e| byte::Error::BadInput {
-                err: "topic_name longer than 256 bytes",
-            })?;
-        Ok((TopicName(s), *offset))
+        let msg_type = read_header(bytes)?;
+        if msg_type == 0xfe {
+            let fw_msg: ForwardedMessage = bytes.read(offset)?;
+            Ok((fw_msg.into(), *offset))
+        } else {
+            let msg: Message = bytes.read(offset)?;
+            Ok((msg.into(), *offset))
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct RegAck {
-    pub topic_id: u16,
-    pub msg_id: u16,
-    pub code: ReturnCode,
-}
+impl WireFormat for MaybeForwardedMessage {
+    fn encoded_len(&self) -> usize {
+        match self {
+            MaybeForwardedMessage::ForwardedMessage(msg) => msg.encoded_len(),
+            MaybeForwardedMessage::Message(msg) => msg.encoded_len(),
+        }
+    }
 
-impl TryWrite for RegAck {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, 7u8)?; // len
-        bytes.write(offset, 0xBu8)?; // msg type
-        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.code)?;
-        Ok(*offset)
+    wire_format_codec!(encode);
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        decode_with(buf)
     }
-}
 
-impl TryRead<'_> for RegAck {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        *offset += 1; // msg type
-        Ok((
-            RegAck {
-                topic_id: bytes.read_with(offset, byte::ctx::BE)?,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                code: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+    fn validate_with(&self, ctx: ParseCtx) -> Result<(), DecodeError> {
+        match self {
+            MaybeForwardedMessage::ForwardedMessage(msg) => msg.validate_with(ctx),
+            MaybeForwardedMessage::Message(msg) => msg.validate_with(ctx),
+        }
+    }
+
+    fn decode_ctx(buf: &[u8], ctx: ParseCtx) -> Result<(Self, usize), DecodeError> {
+        if read_header(buf)? == 0xfe {
+            let (msg, len) = ForwardedMessage::decode_ctx(buf, ctx)?;
+            Ok((msg.into(), len))
+        } else {
+            let (msg, len) = Message::decode_ctx(buf, ctx)?;
+            Ok((msg.into(), len))
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Publish {
-    pub flags: Flags,
-    pub topic_id: u16,
-    pub msg_id: u16,
-    pub data: PublishData,
+pub struct ForwardedMessage {
+    pub ctrl: u8,
+    pub wireless_node_id: WirelessNodeId,
+    pub message: Message,
+}
+
+impl MsgType for ForwardedMessage {
+    const MSG_TYPE: u8 = 0xfe;
 }
 
-impl TryWrite for Publish {
+impl TryWrite for &ForwardedMessage {
     fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
         let offset = &mut 0;
-        let len = 7 + self.data.len() as u8;
-        bytes.write(offset, len)?;
-        bytes.write(offset, 0x0Cu8)?; // msg type
-        bytes.write(offset, self.flags)?;
-        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.data.as_str())?;
+        // The node id holds at most 16 bytes, so the length always fits.
+        bytes.write(offset, 3 + self.wireless_node_id.len() as u8)?; // len
+        bytes.write(offset, 0xFEu8)?; // msg type
+        bytes.write(offset, self.ctrl)?;
+        bytes.write(offset, self.wireless_node_id.as_str())?;
+        bytes.write(offset, &self.message)?;
         Ok(*offset)
     }
 }
 
-impl TryRead<'_> for Publish {
+impl_try_write_by_value!(ForwardedMessage);
+
+impl TryRead<'_> for ForwardedMessage {
     fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
         let offset = &mut 0;
         let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        if len < 7 {
-            return Err(byte::Error::BadInput {
-                err: "Publish len must be >= 6 bytes",
-            });
+        if len < 3 {
+            return Err(DecodeError::BadLength {
+                declared: len as usize,
+                actual: bytes.len(),
+            }
+            .into());
         }
-        *offset += 1; // msg type
+        if len as usize - 3 > 16 {
+            return Err(DecodeError::BadInput("wireless_node_id longer than 16 bytes").into());
+        }
+        bytes.read::<u8>(offset)?; // msg type
         Ok((
-            Publish {
-                flags: bytes.read(offset)?,
-                topic_id: bytes.read_with(offset, byte::ctx::BE)?,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                data: bytes.read_with(offset, len as usize - 7)?,
+            ForwardedMessage {
+                ctrl: bytes.read(offset)?,
+                wireless_node_id: bytes.read_with(offset, len as usize - 3)?,
+                message: bytes.read(offset)?,
             },
             *offset,
         ))
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+impl WireFormat for ForwardedMessage {
+    fn encoded_len(&self) -> usize {
+        3 + self.wireless_node_id.len() + self.message.encoded_len()
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, ctx: ParseCtx) -> Result<(), DecodeError> {
+        self.message.validate_with(ctx)
+    }
+
+    fn decode_ctx(buf: &[u8], ctx: ParseCtx) -> Result<(Self, usize), DecodeError> {
+        let (msg, len) = Self::decode(buf)?;
+        if ctx.strict {
+            // The length field only covers the encapsulation header, so the
+            // length check applies to the inner message instead.
+            Message::decode_ctx(&buf[3 + msg.wireless_node_id.len()..], ctx)?;
+        }
+        Ok((msg, len))
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialOrd, Ord, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct PublishData(heapless::String<256>);
+pub struct WirelessNodeId(heapless::String<16>);
 
-impl PublishData {
+impl WirelessNodeId {
     pub fn new() -> Self {
         Self(String::new())
     }
+
+    /// Like `From<&str>`, but fails instead of panicking if `s` is longer
+    /// than 16 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn try_new(s: &str) -> Result<Self, ()> {
+        let mut id = String::new();
+        id.push_str(s)?;
+        Ok(Self(id))
+    }
 }
 
-impl From<&str> for PublishData {
+impl From<&str> for WirelessNodeId {
     fn from(s: &str) -> Self {
         Self(String::from(s))
     }
 }
 
-impl Deref for PublishData {
-    type Target = heapless::String<256>;
+impl Deref for WirelessNodeId {
+    type Target = heapless::String<16>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl DerefMut for PublishData {
+impl DerefMut for WirelessNodeId {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
-impl TryWrite for PublishData {
+impl TryWrite for &WirelessNodeId {
     fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
         let offset = &mut 0;
         bytes.write(offset, self.as_str())?;
@@ -792,474 +1268,5723 @@ impl TryWrite for PublishData {
     }
 }
 
-impl TryRead<'_, usize> for PublishData {
+impl_try_write_by_value!(WirelessNodeId);
+
+impl TryRead<'_, usize> for WirelessNodeId {
     fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
         let offset = &mut 0;
         let mut s = String::new();
         s.push_str(bytes.read_with(offset, byte::ctx::Str::Len(len))?)
             .map_err(|_e| byte::Error::BadInput {
-                err: "data longer than 256 bytes",
+                err: "wireless_node_id longer than 16 bytes",
             })?;
-        Ok((PublishData(s), *offset))
+        Ok((WirelessNodeId(s), *offset))
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// The type of a [`Message`], without its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct PubAck {
-    pub topic_id: u16,
-    pub msg_id: u16,
-    pub code: ReturnCode,
+pub enum MessageType {
+    SearchGw,
+    GwInfo,
+    Connect,
+    ConnAck,
+    WillTopic,
+    Register,
+    RegAck,
+    Publish,
+    PubAck,
+    Subscribe,
+    SubAck,
+    Unsubscribe,
+    UnsubAck,
+    PingReq,
+    PingResp,
+    Disconnect,
+    Unknown(u8),
 }
 
-impl TryWrite for PubAck {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, 7u8)?; // len
-        bytes.write(offset, 0x0Du8)?; // msg type
-        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.code)?;
-        Ok(*offset)
+impl From<MessageType> for u8 {
+    fn from(msg_type: MessageType) -> u8 {
+        match msg_type {
+            MessageType::SearchGw => 0x01,
+            MessageType::GwInfo => 0x02,
+            MessageType::Connect => 0x04,
+            MessageType::ConnAck => 0x05,
+            MessageType::WillTopic => 0x07,
+            MessageType::Register => 0x0a,
+            MessageType::RegAck => 0x0b,
+            MessageType::Publish => 0x0c,
+            MessageType::PubAck => 0x0d,
+            MessageType::Subscribe => 0x12,
+            MessageType::SubAck => 0x13,
+            MessageType::Unsubscribe => 0x14,
+            MessageType::UnsubAck => 0x15,
+            MessageType::PingReq => 0x16,
+            MessageType::PingResp => 0x17,
+            MessageType::Disconnect => 0x18,
+            MessageType::Unknown(t) => t,
+        }
     }
 }
 
-impl TryRead<'_> for PubAck {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let _len: u8 = bytes.read(offset)?;
-        *offset += 1; // msg type
-        Ok((
-            PubAck {
-                topic_id: bytes.read_with(offset, byte::ctx::BE)?,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                code: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+/// Largest encoding of any message of type `msg_type` with its variable
+/// fields filled to capacity, for sizing buffers. Types with a 1-byte length
+/// field are capped at 255.
+pub fn max_encoded_len(msg_type: MessageType) -> usize {
+    match msg_type {
+        MessageType::SearchGw => 3,
+        MessageType::GwInfo => 3 + GwAddress::MAX_LEN,
+        MessageType::Connect => 6 + 64,
+        MessageType::ConnAck => 3 + MAX_EXTENSION_LEN,
+        MessageType::WillTopic => 0xff,
+        MessageType::Register => len_with_header(5 + 256),
+        MessageType::RegAck => 7 + MAX_EXTENSION_LEN,
+        MessageType::Publish => len_with_header(6 + 256),
+        MessageType::PubAck => 7,
+        MessageType::Subscribe => len_with_header(4 + 256),
+        MessageType::SubAck => 8,
+        MessageType::Unsubscribe => len_with_header(4 + 256),
+        MessageType::UnsubAck => 5,
+        MessageType::PingReq => 2 + 64,
+        MessageType::PingResp => 2,
+        MessageType::Disconnect => 4,
+        MessageType::Unknown(_) => len_with_header(1 + 256),
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[allow(clippy::large_enum_variant)]
-pub enum TopicNameOrId {
-    Name(TopicName),
-    Id(u16),
+pub enum Message {
+    SearchGw(SearchGw),
+    GwInfo(GwInfo),
+    Connect(Connect),
+    ConnAck(ConnAck),
+    WillTopic(WillTopic),
+    Register(Register),
+    RegAck(RegAck),
+    Publish(Publish),
+    PubAck(PubAck),
+    Subscribe(Subscribe),
+    SubAck(SubAck),
+    Unsubscribe(Unsubscribe),
+    UnsubAck(UnsubAck),
+    PingReq(PingReq),
+    PingResp(PingResp),
+    Disconnect(Disconnect),
+    Unknown(Unknown),
 }
 
-impl TryWrite for TopicNameOrId {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        match self {
-            Self::Id(id) => bytes.write_with(offset, id, byte::ctx::BE)?,
-            Self::Name(name) => bytes.write(offset, name)?,
-        }
-        Ok(*offset)
+/// A PINGRESP, used only as a placeholder when filling containers. It
+/// carries no meaning of its own.
+impl Default for Message {
+    fn default() -> Self {
+        Message::PingResp(PingResp {})
     }
 }
 
-impl TryRead<'_, (Flags, usize)> for TopicNameOrId {
-    fn try_read(bytes: &[u8], ctx: (Flags, usize)) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        Ok((
-            if ctx.0.topic_id_type() == 0 {
-                Self::Name(bytes.read_with(offset, ctx.1)?)
-            } else {
-                Self::Id(bytes.read_with(offset, byte::ctx::BE)?)
-            },
-            *offset,
-        ))
+impl From<SearchGw> for Message {
+    fn from(msg: SearchGw) -> Self {
+        Message::SearchGw(msg)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Subscribe {
-    pub flags: Flags,
-    pub msg_id: u16,
-    pub topic: TopicNameOrId,
+impl From<GwInfo> for Message {
+    fn from(msg: GwInfo) -> Self {
+        Message::GwInfo(msg)
+    }
 }
 
-impl MsgType for Subscribe {
-    const MSG_TYPE: u8 = 0x12;
+impl From<Connect> for Message {
+    fn from(msg: Connect) -> Self {
+        Message::Connect(msg)
+    }
 }
 
-impl TryWrite for Subscribe {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 1; // len is written last
-        bytes.write(offset, Self::MSG_TYPE)?;
-        bytes.write(offset, self.flags)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.topic)?;
-        bytes.write(&mut 0, *offset as u8)?; // len
-        Ok(*offset)
+impl From<ConnAck> for Message {
+    fn from(msg: ConnAck) -> Self {
+        Message::ConnAck(msg)
     }
 }
 
-impl TryRead<'_> for Subscribe {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        *offset += 1; // msg type
-        let flags = bytes.read(offset)?;
-        Ok((
-            Self {
-                flags,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                topic: bytes.read_with(offset, (flags, len as usize - 5))?,
-            },
-            *offset,
-        ))
+impl From<WillTopic> for Message {
+    fn from(msg: WillTopic) -> Self {
+        Message::WillTopic(msg)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct SubAck {
-    pub flags: Flags,
-    pub msg_id: u16,
-    pub topic_id: u16,
-    pub code: ReturnCode,
+impl From<Register> for Message {
+    fn from(msg: Register) -> Self {
+        Message::Register(msg)
+    }
 }
 
-impl MsgType for SubAck {
-    const MSG_TYPE: u8 = 0x13;
+impl From<RegAck> for Message {
+    fn from(msg: RegAck) -> Self {
+        Message::RegAck(msg)
+    }
 }
 
-impl TryWrite for SubAck {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, 7u8)?; // len
-        bytes.write(offset, Self::MSG_TYPE)?;
-        bytes.write(offset, self.flags)?;
-        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.code)?;
-        Ok(*offset)
+impl From<Publish> for Message {
+    fn from(msg: Publish) -> Self {
+        Message::Publish(msg)
     }
 }
 
-impl TryRead<'_> for SubAck {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let _len: u8 = bytes.read(offset)?;
-        *offset += 1; // msg type
-        Ok((
-            Self {
-                flags: bytes.read(offset)?,
-                topic_id: bytes.read_with(offset, byte::ctx::BE)?,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                code: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+impl From<PubAck> for Message {
+    fn from(msg: PubAck) -> Self {
+        Message::PubAck(msg)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Unsubscribe {
-    pub flags: Flags,
-    pub msg_id: u16,
-    pub topic: TopicNameOrId,
+impl From<Subscribe> for Message {
+    fn from(msg: Subscribe) -> Self {
+        Message::Subscribe(msg)
+    }
 }
 
-impl MsgType for Unsubscribe {
-    const MSG_TYPE: u8 = 0x14;
+impl From<SubAck> for Message {
+    fn from(msg: SubAck) -> Self {
+        Message::SubAck(msg)
+    }
 }
 
-impl TryWrite for Unsubscribe {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 1; // len is written last
-        bytes.write(offset, Self::MSG_TYPE)?;
-        bytes.write(offset, self.flags)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.topic)?;
-        bytes.write(&mut 0, *offset as u8)?; // len
-        Ok(*offset)
+impl From<Unsubscribe> for Message {
+    fn from(msg: Unsubscribe) -> Self {
+        Message::Unsubscribe(msg)
     }
 }
 
-impl TryRead<'_> for Unsubscribe {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        *offset += 1; // msg type
-        let flags = bytes.read(offset)?;
-        Ok((
-            Self {
-                flags,
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                topic: bytes.read_with(offset, (flags, len as usize - 5))?,
-            },
-            *offset,
-        ))
+impl From<UnsubAck> for Message {
+    fn from(msg: UnsubAck) -> Self {
+        Message::UnsubAck(msg)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct UnsubAck {
-    pub msg_id: u16,
-    pub code: ReturnCode,
+impl From<PingReq> for Message {
+    fn from(msg: PingReq) -> Self {
+        Message::PingReq(msg)
+    }
 }
 
-impl MsgType for UnsubAck {
-    const MSG_TYPE: u8 = 0x15;
+impl From<PingResp> for Message {
+    fn from(msg: PingResp) -> Self {
+        Message::PingResp(msg)
+    }
 }
 
-impl TryWrite for UnsubAck {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
-        let offset = &mut 0;
-        bytes.write(offset, 5u8)?; // len
-        bytes.write(offset, Self::MSG_TYPE)?;
-        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
-        bytes.write(offset, self.code)?;
-        Ok(*offset)
+impl From<Disconnect> for Message {
+    fn from(msg: Disconnect) -> Self {
+        Message::Disconnect(msg)
     }
 }
 
-impl TryRead<'_> for UnsubAck {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let _len: u8 = bytes.read(offset)?;
-        *offset += 1; // msg type
-        Ok((
-            Self {
-                msg_id: bytes.read_with(offset, byte::ctx::BE)?,
-                code: bytes.read(offset)?,
-            },
-            *offset,
-        ))
+impl From<Unknown> for Message {
+    fn from(msg: Unknown) -> Self {
+        Message::Unknown(msg)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct PingReq {
-    pub client_id: ClientId,
-}
+impl Message {
+    pub fn search_gw(radius: u8) -> Self {
+        Message::SearchGw(SearchGw { radius })
+    }
 
-impl TryWrite for PingReq {
-    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+    pub fn gw_info(gw_id: u8) -> Self {
+        Message::GwInfo(GwInfo {
+            gw_id,
+            gw_add: None,
+        })
+    }
+
+    pub fn connect(client_id: &str, duration: u16, clean_session: bool, will: bool) -> Self {
+        let mut flags = Flags::default();
+        flags.set_clean_session(clean_session);
+        flags.set_will(will);
+        Message::Connect(Connect {
+            flags,
+            duration,
+            client_id: client_id.into(),
+        })
+    }
+
+    pub fn conn_ack(code: ReturnCode) -> Self {
+        Message::ConnAck(ConnAck {
+            code,
+            extra: ByteBuf::new(),
+        })
+    }
+
+    pub fn will_topic(topic: &str, qos: QoS, retain: bool) -> Self {
+        Message::WillTopic(WillTopic::new(topic, qos, retain))
+    }
+
+    pub fn register(topic_id: u16, msg_id: u16, topic_name: &str) -> Self {
+        Message::Register(Register {
+            topic_id,
+            msg_id,
+            topic_name: topic_name.into(),
+        })
+    }
+
+    pub fn reg_ack(topic_id: u16, msg_id: u16, code: ReturnCode) -> Self {
+        Message::RegAck(RegAck {
+            topic_id,
+            msg_id,
+            code,
+            extra: ByteBuf::new(),
+        })
+    }
+
+    pub fn publish(flags: Flags, topic_id: u16, msg_id: u16, data: &str) -> Self {
+        Message::Publish(Publish {
+            flags,
+            topic_id,
+            msg_id,
+            data: data.into(),
+        })
+    }
+
+    pub fn pub_ack(topic_id: u16, msg_id: u16, code: ReturnCode) -> Self {
+        Message::PubAck(PubAck {
+            topic_id,
+            msg_id,
+            code,
+        })
+    }
+
+    pub fn subscribe(flags: Flags, msg_id: u16, topic: TopicNameOrId) -> Self {
+        Message::Subscribe(Subscribe {
+            flags,
+            msg_id,
+            topic,
+        })
+    }
+
+    pub fn sub_ack(flags: Flags, msg_id: u16, topic_id: u16, code: ReturnCode) -> Self {
+        Message::SubAck(SubAck {
+            flags,
+            msg_id,
+            topic_id,
+            code,
+        })
+    }
+
+    pub fn unsubscribe(flags: Flags, msg_id: u16, topic: TopicNameOrId) -> Self {
+        Message::Unsubscribe(Unsubscribe {
+            flags,
+            msg_id,
+            topic,
+        })
+    }
+
+    pub fn unsub_ack(msg_id: u16, code: ReturnCode) -> Self {
+        Message::UnsubAck(UnsubAck { msg_id, code })
+    }
+
+    pub fn ping_req(client_id: Option<&str>) -> Self {
+        Message::PingReq(PingReq {
+            client_id: client_id.map(ClientId::from),
+        })
+    }
+
+    pub fn ping_resp() -> Self {
+        Message::PingResp(PingResp {})
+    }
+
+    pub fn disconnect(duration: Option<u16>) -> Self {
+        Message::Disconnect(Disconnect { duration })
+    }
+
+    pub fn as_search_gw(&self) -> Option<&SearchGw> {
+        match self {
+            Message::SearchGw(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_gw_info(&self) -> Option<&GwInfo> {
+        match self {
+            Message::GwInfo(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_connect(&self) -> Option<&Connect> {
+        match self {
+            Message::Connect(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_conn_ack(&self) -> Option<&ConnAck> {
+        match self {
+            Message::ConnAck(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_will_topic(&self) -> Option<&WillTopic> {
+        match self {
+            Message::WillTopic(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_register(&self) -> Option<&Register> {
+        match self {
+            Message::Register(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_reg_ack(&self) -> Option<&RegAck> {
+        match self {
+            Message::RegAck(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_publish(&self) -> Option<&Publish> {
+        match self {
+            Message::Publish(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_pub_ack(&self) -> Option<&PubAck> {
+        match self {
+            Message::PubAck(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_subscribe(&self) -> Option<&Subscribe> {
+        match self {
+            Message::Subscribe(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_sub_ack(&self) -> Option<&SubAck> {
+        match self {
+            Message::SubAck(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_unsubscribe(&self) -> Option<&Unsubscribe> {
+        match self {
+            Message::Unsubscribe(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_unsub_ack(&self) -> Option<&UnsubAck> {
+        match self {
+            Message::UnsubAck(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_ping_req(&self) -> Option<&PingReq> {
+        match self {
+            Message::PingReq(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_ping_resp(&self) -> Option<&PingResp> {
+        match self {
+            Message::PingResp(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn as_disconnect(&self) -> Option<&Disconnect> {
+        match self {
+            Message::Disconnect(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Key that orders messages by their wire type code.
+    pub fn sort_key(&self) -> u8 {
+        self.msg_type().into()
+    }
+
+    pub fn msg_type(&self) -> MessageType {
+        match self {
+            Message::SearchGw(_) => MessageType::SearchGw,
+            Message::GwInfo(_) => MessageType::GwInfo,
+            Message::Connect(_) => MessageType::Connect,
+            Message::ConnAck(_) => MessageType::ConnAck,
+            Message::WillTopic(_) => MessageType::WillTopic,
+            Message::Register(_) => MessageType::Register,
+            Message::RegAck(_) => MessageType::RegAck,
+            Message::Publish(_) => MessageType::Publish,
+            Message::PubAck(_) => MessageType::PubAck,
+            Message::Subscribe(_) => MessageType::Subscribe,
+            Message::SubAck(_) => MessageType::SubAck,
+            Message::Unsubscribe(_) => MessageType::Unsubscribe,
+            Message::UnsubAck(_) => MessageType::UnsubAck,
+            Message::PingReq(_) => MessageType::PingReq,
+            Message::PingResp(_) => MessageType::PingResp,
+            Message::Disconnect(_) => MessageType::Disconnect,
+            Message::Unknown(msg) => MessageType::Unknown(msg.msg_type),
+        }
+    }
+
+    /// Calls the [`MessageVisitor`] method for this message's type.
+    pub fn accept(&self, visitor: &mut impl MessageVisitor) {
+        match self {
+            Message::SearchGw(msg) => visitor.visit_search_gw(msg),
+            Message::GwInfo(msg) => visitor.visit_gw_info(msg),
+            Message::Connect(msg) => visitor.visit_connect(msg),
+            Message::ConnAck(msg) => visitor.visit_conn_ack(msg),
+            Message::WillTopic(msg) => visitor.visit_will_topic(msg),
+            Message::Register(msg) => visitor.visit_register(msg),
+            Message::RegAck(msg) => visitor.visit_reg_ack(msg),
+            Message::Publish(msg) => visitor.visit_publish(msg),
+            Message::PubAck(msg) => visitor.visit_pub_ack(msg),
+            Message::Subscribe(msg) => visitor.visit_subscribe(msg),
+            Message::SubAck(msg) => visitor.visit_sub_ack(msg),
+            Message::Unsubscribe(msg) => visitor.visit_unsubscribe(msg),
+            Message::UnsubAck(msg) => visitor.visit_unsub_ack(msg),
+            Message::PingReq(msg) => visitor.visit_ping_req(msg),
+            Message::PingResp(msg) => visitor.visit_ping_resp(msg),
+            Message::Disconnect(msg) => visitor.visit_disconnect(msg),
+            Message::Unknown(msg) => visitor.visit_unknown(msg),
+        }
+    }
+
+    /// Uppercase name of the message type as the spec writes it, e.g.
+    /// `"PUBLISH"`. Stable for use as a metrics label.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Message::SearchGw(_) => "SEARCHGW",
+            Message::GwInfo(_) => "GWINFO",
+            Message::Connect(_) => "CONNECT",
+            Message::ConnAck(_) => "CONNACK",
+            Message::WillTopic(_) => "WILLTOPIC",
+            Message::Register(_) => "REGISTER",
+            Message::RegAck(_) => "REGACK",
+            Message::Publish(_) => "PUBLISH",
+            Message::PubAck(_) => "PUBACK",
+            Message::Subscribe(_) => "SUBSCRIBE",
+            Message::SubAck(_) => "SUBACK",
+            Message::Unsubscribe(_) => "UNSUBSCRIBE",
+            Message::UnsubAck(_) => "UNSUBACK",
+            Message::PingReq(_) => "PINGREQ",
+            Message::PingResp(_) => "PINGRESP",
+            Message::Disconnect(_) => "DISCONNECT",
+            Message::Unknown(_) => "UNKNOWN",
+        }
+    }
+
+    /// Number of bytes this message takes when encoded.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Message::SearchGw(msg) => msg.encoded_len(),
+            Message::GwInfo(msg) => msg.encoded_len(),
+            Message::Connect(msg) => msg.encoded_len(),
+            Message::ConnAck(msg) => msg.encoded_len(),
+            Message::WillTopic(msg) => msg.encoded_len(),
+            Message::Register(msg) => msg.encoded_len(),
+            Message::RegAck(msg) => msg.encoded_len(),
+            Message::Publish(msg) => msg.encoded_len(),
+            Message::PubAck(msg) => msg.encoded_len(),
+            Message::Subscribe(msg) => msg.encoded_len(),
+            Message::SubAck(msg) => msg.encoded_len(),
+            Message::Unsubscribe(msg) => msg.encoded_len(),
+            Message::UnsubAck(msg) => msg.encoded_len(),
+            Message::PingReq(msg) => msg.encoded_len(),
+            Message::PingResp(msg) => msg.encoded_len(),
+            Message::Disconnect(msg) => msg.encoded_len(),
+            Message::Unknown(msg) => msg.encoded_len(),
+        }
+    }
+
+    /// Parses a message into `self`, returning the number of bytes consumed.
+    /// When both `self` and the incoming message are PUBLISHes, the payload
+    /// is copied into place with [`Publish::decode_into`]; otherwise `self`
+    /// is replaced. `self` is left as is on error.
+    pub fn decode_into(&mut self, buf: &[u8]) -> Result<usize, DecodeError> {
+        if let Message::Publish(msg) = self {
+            if read_header(buf)? == u8::from(MessageType::Publish) {
+                return msg.decode_into(buf);
+            }
+        }
+        let (msg, len) = decode(buf)?;
+        *self = msg;
+        Ok(len)
+    }
+
+    /// Number of bytes after the length field: the type byte and the rest
+    /// of the body.
+    pub fn body_len(&self) -> usize {
+        let len = self.encoded_len();
+        if len <= 0xff {
+            len - 1
+        } else {
+            len - 3
+        }
+    }
+
+    /// Encodes the message only if it fits in `bytes`, so a failed encode
+    /// leaves the buffer untouched.
+    pub fn encode_exact(&self, bytes: &mut [u8]) -> byte::Result<usize> {
+        check_len(bytes, self.encoded_len())?;
         let offset = &mut 0;
-        let len = 2 + self.client_id.len() as u8;
-        bytes.write(offset, len)?;
-        bytes.write(offset, 0x16u8)?; // msg type
-        bytes.write(offset, self.client_id.as_str())?;
+        bytes.write(offset, self)?;
         Ok(*offset)
     }
-}
 
-impl TryRead<'_> for PingReq {
-    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+    /// Encodes the message into `buf` like [`Message::encode_exact`], telling
+    /// apart a buffer that's too small from a message that can't be encoded.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        encode_checked(self, self.encoded_len(), buf)
+    }
+
+    /// Parses a single message like `TryRead`, applying the checks selected
+    /// by `ctx`. Returns the message and the number of bytes consumed.
+    pub fn parse(bytes: &[u8], ctx: ParseCtx) -> byte::Result<(Self, usize)> {
+        Ok(Self::decode_ctx(bytes, ctx)?)
+    }
+
+    /// Writes the message without its length field, starting at the type
+    /// byte, for transports that do their own length framing.
+    pub fn write_body(&self, bytes: &mut [u8]) -> byte::Result<usize> {
         let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        if len < 2 {
-            return Err(byte::Error::BadInput {
-                err: "Len must be at least 2 bytes",
-            });
+        bytes.write(offset, u8::from(self.msg_type()))?;
+        match self {
+            Message::SearchGw(msg) => msg.write_body(bytes, offset),
+            Message::GwInfo(msg) => msg.write_body(bytes, offset),
+            Message::Connect(msg) => msg.write_body(bytes, offset),
+            Message::ConnAck(msg) => msg.write_body(bytes, offset),
+            Message::WillTopic(msg) => msg.write_body(bytes, offset),
+            Message::Register(msg) => msg.write_body(bytes, offset),
+            Message::RegAck(msg) => msg.write_body(bytes, offset),
+            Message::Publish(msg) => msg.write_body(bytes, offset),
+            Message::PubAck(msg) => msg.write_body(bytes, offset),
+            Message::Subscribe(msg) => msg.write_body(bytes, offset),
+            Message::SubAck(msg) => msg.write_body(bytes, offset),
+            Message::Unsubscribe(msg) => msg.write_body(bytes, offset),
+            Message::UnsubAck(msg) => msg.write_body(bytes, offset),
+            Message::PingReq(msg) => msg.write_body(bytes, offset),
+            Message::PingResp(msg) => msg.write_body(bytes, offset),
+            Message::Disconnect(msg) => msg.write_body(bytes, offset),
+            Message::Unknown(msg) => bytes.write(offset, msg.body.as_slice()),
+        }?;
+        Ok(*offset)
+    }
+
+    /// Parses a message written by [`Message::write_body`]. `msg_type` is
+    /// the first byte `write_body` wrote and `bytes` everything after it, so
+    /// `parse_body(buf[0], &buf[1..len])` reads back a body of `len` bytes.
+    /// The length of `bytes` is taken as the length of the fields.
+    pub fn parse_body(msg_type: u8, bytes: &[u8]) -> byte::Result<Self> {
+        let offset = &mut 0;
+        Ok(match msg_type {
+            0x01 => Message::SearchGw(SearchGw::read_body(bytes, offset)?),
+            0x02 => Message::GwInfo(GwInfo::read_body(bytes, offset)?),
+            0x04 => Message::Connect(Connect::read_body(bytes, offset)?),
+            0x05 => Message::ConnAck(ConnAck::read_body(bytes, offset)?),
+            WillTopic::MSG_TYPE => Message::WillTopic(WillTopic::read_body(bytes, offset)?),
+            0x0a => Message::Register(Register::read_body(bytes, offset)?),
+            0x0b => Message::RegAck(RegAck::read_body(bytes, offset)?),
+            0x0c => Message::Publish(Publish::read_body(bytes, offset)?),
+            0x0d => Message::PubAck(PubAck::read_body(bytes, offset)?),
+            Subscribe::MSG_TYPE => Message::Subscribe(Subscribe::read_body(bytes, offset)?),
+            SubAck::MSG_TYPE => Message::SubAck(SubAck::read_body(bytes, offset)?),
+            Unsubscribe::MSG_TYPE => Message::Unsubscribe(Unsubscribe::read_body(bytes, offset)?),
+            UnsubAck::MSG_TYPE => Message::UnsubAck(UnsubAck::read_body(bytes, offset)?),
+            0x16 => Message::PingReq(PingReq::read_body(bytes, offset)?),
+            0x17 => Message::PingResp(PingResp::read_body(bytes, offset)?),
+            Disconnect::MSG_TYPE => Message::Disconnect(Disconnect::read_body(bytes, offset)?),
+            t if is_reserved_type(t) => Message::Unknown(
+                UnknownRef {
+                    msg_type,
+                    body: bytes,
+                }
+                .try_into()?,
+            ),
+            t => return Err(DecodeError::UnknownMessageType(t).into()),
+        })
+    }
+
+    /// Checks the constraints that strict parsing enforces on top of the wire
+    /// format.
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        self.validate_with(ParseCtx::default())
+    }
+
+    /// Compares two messages, treating PUBLISHes that differ only in the DUP
+    /// flag as equal since it just marks a retransmission.
+    pub fn eq_ignoring_dup(&self, other: &Message) -> bool {
+        match (self, other) {
+            (Message::Publish(a), Message::Publish(b)) => {
+                a.flags.semantic_eq(&b.flags, !Flags::DUP)
+                    && a.topic_id == b.topic_id
+                    && a.msg_id == b.msg_id
+                    && a.data == b.data
+            }
+            _ => self == other,
         }
-        *offset += 1; // msg type
-        Ok((
-            PingReq {
-                client_id: bytes.read_with(offset, len as usize - 2)?,
+    }
+
+    /// Whether the message is part of connection setup: CONNECT, CONNACK
+    /// and the will exchange.
+    pub fn is_connection_setup(&self) -> bool {
+        matches!(
+            self,
+            Message::Connect(_) | Message::ConnAck(_) | Message::WillTopic(_)
+        )
+    }
+
+    pub fn is_keepalive(&self) -> bool {
+        matches!(self, Message::PingReq(_) | Message::PingResp(_))
+    }
+
+    /// Whether the message is a PUBLISH or one of its acknowledgements.
+    pub fn is_publish_flow(&self) -> bool {
+        matches!(self, Message::Publish(_) | Message::PubAck(_))
+    }
+
+    /// Returns the topic id this message refers to, if any. Short topic names
+    /// are not topic ids and yield `None`.
+    pub fn affects_topic_id(&self) -> Option<u16> {
+        match self {
+            Message::Register(msg) => Some(msg.topic_id),
+            Message::RegAck(msg) => Some(msg.topic_id),
+            Message::Publish(msg) => match TopicIdType::from(msg.flags.topic_id_type()) {
+                TopicIdType::Normal | TopicIdType::Predefined => Some(msg.topic_id),
+                _ => None,
             },
-            *offset,
-        ))
+            Message::PubAck(msg) => Some(msg.topic_id),
+            Message::Subscribe(Subscribe {
+                flags,
+                topic: TopicNameOrId::Id(id),
+                ..
+            })
+            | Message::Unsubscribe(Unsubscribe {
+                flags,
+                topic: TopicNameOrId::Id(id),
+                ..
+            }) => match TopicIdType::from(flags.topic_id_type()) {
+                TopicIdType::Predefined => Some(*id),
+                _ => None,
+            },
+            Message::SubAck(msg) if msg.topic_id != 0 => Some(msg.topic_id),
+            _ => None,
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct PingResp {}
+/// Messages are ordered by type code first, then by their fields.
+impl Ord for Message {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort_key()
+            .cmp(&other.sort_key())
+            .then_with(|| match (self, other) {
+                (Message::SearchGw(a), Message::SearchGw(b)) => a.cmp(b),
+                (Message::GwInfo(a), Message::GwInfo(b)) => a.cmp(b),
+                (Message::Connect(a), Message::Connect(b)) => a.cmp(b),
+                (Message::ConnAck(a), Message::ConnAck(b)) => a.cmp(b),
+                (Message::WillTopic(a), Message::WillTopic(b)) => a.cmp(b),
+                (Message::Register(a), Message::Register(b)) => a.cmp(b),
+                (Message::RegAck(a), Message::RegAck(b)) => a.cmp(b),
+                (Message::Publish(a), Message::Publish(b)) => a.cmp(b),
+                (Message::PubAck(a), Message::PubAck(b)) => a.cmp(b),
+                (Message::Subscribe(a), Message::Subscribe(b)) => a.cmp(b),
+                (Message::SubAck(a), Message::SubAck(b)) => a.cmp(b),
+                (Message::Unsubscribe(a), Message::Unsubscribe(b)) => a.cmp(b),
+                (Message::UnsubAck(a), Message::UnsubAck(b)) => a.cmp(b),
+                (Message::PingReq(a), Message::PingReq(b)) => a.cmp(b),
+                (Message::PingResp(a), Message::PingResp(b)) => a.cmp(b),
+                (Message::Disconnect(a), Message::Disconnect(b)) => a.cmp(b),
+                (Message::Unknown(a), Message::Unknown(b)) => a.cmp(b),
+                // Same type code but different variants means one side is an
+                // `Unknown` carrying an assigned code; order it after the
+                // parsed message so unequal messages never compare equal.
+                _ => matches!(self, Message::Unknown(_)).cmp(&matches!(other, Message::Unknown(_))),
+            })
+    }
+}
+
+impl PartialOrd for Message {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-impl TryWrite for PingResp {
+impl TryWrite for &Message {
     fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
         let offset = &mut 0;
-        bytes.write(offset, 2u8)?; // len
-        bytes.write(offset, 0x17u8)?; // msg type
+        match self {
+            Message::SearchGw(msg) => bytes.write(offset, msg),
+            Message::GwInfo(msg) => bytes.write(offset, msg),
+            Message::Connect(msg) => bytes.write(offset, msg),
+            Message::ConnAck(msg) => bytes.write(offset, msg),
+            Message::WillTopic(msg) => bytes.write(offset, msg),
+            Message::Register(msg) => bytes.write(offset, msg),
+            Message::RegAck(msg) => bytes.write(offset, msg),
+            Message::Publish(msg) => bytes.write(offset, msg),
+            Message::PubAck(msg) => bytes.write(offset, msg),
+            Message::Subscribe(msg) => bytes.write(offset, msg),
+            Message::SubAck(msg) => bytes.write(offset, msg),
+            Message::Unsubscribe(msg) => bytes.write(offset, msg),
+            Message::UnsubAck(msg) => bytes.write(offset, msg),
+            Message::PingReq(msg) => bytes.write(offset, msg),
+            Message::PingResp(msg) => bytes.write(offset, msg),
+            Message::Disconnect(msg) => bytes.write(offset, msg),
+            Message::Unknown(msg) => bytes.write(offset, msg),
+        }?;
         Ok(*offset)
     }
 }
 
-impl TryRead<'_> for PingResp {
+impl_try_write_by_value!(Message);
+
+impl TryRead<'_> for Message {
     fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
-        let offset = &mut 0;
-        let len: u8 = bytes.read(offset)?;
-        check_len(bytes, len as usize)?;
-        *offset += 1; // msg type
-        Ok((PingResp {}, *offset))
+        let (msg, len) = MessageRef::try_read(bytes, ())?;
+        Ok((Message::try_from(&msg)?, len))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use assert_hex::*;
+/// Iterates over messages packed back to back in one buffer. At most
+/// `max_messages` are parsed; if bytes remain after that, a final error is
+/// yielded instead of continuing. Iteration also stops after a parse error.
+///
+/// Yields [`Message`]s by default; see [`ForwardedMessageIter`] for buffers
+/// that may also contain forwarded messages.
+pub struct MessageIter<'a, T = Message> {
+    bytes: &'a [u8],
+    offset: usize,
+    remaining: usize,
+    done: bool,
+    ctx: ParseCtx,
+    _item: PhantomData<T>,
+}
 
-    use super::*;
+pub type ForwardedMessageIter<'a> = MessageIter<'a, MaybeForwardedMessage>;
+
+impl<'a, T> MessageIter<'a, T> {
+    fn with_max(bytes: &'a [u8], max_messages: usize) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+            remaining: max_messages,
+            done: false,
+            ctx: ParseCtx::default(),
+            _item: PhantomData,
+        }
+    }
 
-    #[test]
-    fn forwarded_message_encode_parse() {
-        let mut bytes = [0u8; 20];
+    /// Parses each message with the checks selected by `ctx`.
+    pub fn with_ctx(mut self, ctx: ParseCtx) -> Self {
+        self.ctx = ctx;
+        self
+    }
+}
+
+impl<'a> MessageIter<'a> {
+    pub fn new(bytes: &'a [u8], max_messages: usize) -> Self {
+        Self::with_max(bytes, max_messages)
+    }
+}
+
+impl<'a> ForwardedMessageIter<'a> {
+    pub fn new_forwarded(bytes: &'a [u8], max_messages: usize) -> Self {
+        Self::with_max(bytes, max_messages)
+    }
+}
+
+impl<T: WireFormat> Iterator for MessageIter<'_, T> {
+    type Item = byte::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.bytes.len() {
+            return None;
+        }
+        if self.remaining == 0 {
+            self.done = true;
+            return Some(Err(byte::Error::BadInput {
+                err: "Too many messages in buffer",
+            }));
+        }
+        self.remaining -= 1;
+        let result = match T::decode_ctx(&self.bytes[self.offset..], self.ctx) {
+            Ok((msg, len)) => {
+                self.offset += len;
+                Ok(msg)
+            }
+            Err(err) => Err(err.into()),
+        };
+        self.done = result.is_err();
+        Some(result)
+    }
+}
+
+/// Adapters for iterators of parsed messages, such as [`MessageIter`].
+pub trait MessageStreamExt: Iterator<Item = byte::Result<Message>> + Sized {
+    /// Keeps only messages of `msg_type`. Errors are passed through.
+    fn of_type(self, msg_type: MessageType) -> OfType<Self> {
+        OfType {
+            iter: self,
+            msg_type,
+        }
+    }
+
+    /// Keeps only PUBLISH messages. Errors are passed through.
+    fn only_publishes(self) -> OfType<Self> {
+        self.of_type(MessageType::Publish)
+    }
+}
+
+impl<I: Iterator<Item = byte::Result<Message>>> MessageStreamExt for I {}
+
+/// Iterator returned by [`MessageStreamExt::of_type`].
+pub struct OfType<I> {
+    iter: I,
+    msg_type: MessageType,
+}
+
+impl<I: Iterator<Item = byte::Result<Message>>> Iterator for OfType<I> {
+    type Item = byte::Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let msg_type = self.msg_type;
+        self.iter.find(|result| match result {
+            Ok(msg) => msg.msg_type() == msg_type,
+            Err(_) => true,
+        })
+    }
+}
+
+/// Per-type handlers called by [`Message::accept`]. Every method defaults to
+/// doing nothing, so implementors only override the types they handle.
+pub trait MessageVisitor {
+    fn visit_search_gw(&mut self, _msg: &SearchGw) {}
+    fn visit_gw_info(&mut self, _msg: &GwInfo) {}
+    fn visit_connect(&mut self, _msg: &Connect) {}
+    fn visit_conn_ack(&mut self, _msg: &ConnAck) {}
+    fn visit_will_topic(&mut self, _msg: &WillTopic) {}
+    fn visit_register(&mut self, _msg: &Register) {}
+    fn visit_reg_ack(&mut self, _msg: &RegAck) {}
+    fn visit_publish(&mut self, _msg: &Publish) {}
+    fn visit_pub_ack(&mut self, _msg: &PubAck) {}
+    fn visit_subscribe(&mut self, _msg: &Subscribe) {}
+    fn visit_sub_ack(&mut self, _msg: &SubAck) {}
+    fn visit_unsubscribe(&mut self, _msg: &Unsubscribe) {}
+    fn visit_unsub_ack(&mut self, _msg: &UnsubAck) {}
+    fn visit_ping_req(&mut self, _msg: &PingReq) {}
+    fn visit_ping_resp(&mut self, _msg: &PingResp) {}
+    fn visit_disconnect(&mut self, _msg: &Disconnect) {}
+    fn visit_unknown(&mut self, _msg: &Unknown) {}
+}
+
+/// Hands out msg ids for messages that need one. Ids wrap around but 0, which
+/// is reserved, is never produced.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MsgIdAllocator {
+    last: u16,
+}
+
+impl MsgIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allocate(&mut self) -> u16 {
+        self.last = self.last.wrapping_add(1);
+        if self.last == 0 {
+            self.last = 1;
+        }
+        self.last
+    }
+}
+
+/// Encodes `msgs` back to back into a single buffer, e.g. for flushing a
+/// batch of messages in one datagram. Fails if they don't fit in `CAP` bytes.
+pub fn encode_batch<const CAP: usize>(msgs: &[Message]) -> byte::Result<heapless::Vec<u8, CAP>> {
+    encode_to_vec(msgs)
+}
+
+fn encode_to_vec<T: TryWrite, const N: usize>(
+    msgs: impl IntoIterator<Item = T>,
+) -> byte::Result<heapless::Vec<u8, N>> {
+    let mut bytes = heapless::Vec::new();
+    bytes.resize_default(N).ok();
+    let offset = &mut 0;
+    for msg in msgs {
+        bytes.write(offset, msg)?;
+    }
+    bytes.truncate(*offset);
+    Ok(bytes)
+}
+
+/// Implements `TryFrom<T> for heapless::Vec<u8, N>`, encoding the message.
+macro_rules! impl_try_from_for_vec {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl<const N: usize> TryFrom<$ty> for heapless::Vec<u8, N> {
+                type Error = byte::Error;
+
+                fn try_from(msg: $ty) -> byte::Result<Self> {
+                    encode_to_vec([msg])
+                }
+            }
+        )*
+    };
+}
+
+impl_try_from_for_vec!(
+    Message,
+    SearchGw,
+    GwInfo,
+    Connect,
+    ConnAck,
+    WillTopic,
+    Register,
+    RegAck,
+    Publish,
+    PubAck,
+    Subscribe,
+    SubAck,
+    Unsubscribe,
+    UnsubAck,
+    PingReq,
+    PingResp,
+    Disconnect,
+    Unknown,
+);
+
+/// Byte offset of the big-endian msg_id within an encoded message of type
+/// `msg_type`, or `None` if the type has no msg_id. `extended_len` selects
+/// the 3-byte length field.
+pub fn msg_id_offset(msg_type: u8, extended_len: bool) -> Option<usize> {
+    let header = if extended_len { 3 } else { 1 };
+    let after_type = match msg_type {
+        0x15 => 0,               // UNSUBACK
+        0x12 | 0x14 => 1,        // SUBSCRIBE, UNSUBSCRIBE: flags
+        0x0a | 0x0b | 0x0d => 2, // REGISTER, REGACK, PUBACK: topic_id
+        0x0c | 0x13 => 3,        // PUBLISH, SUBACK: flags, topic_id
+        _ => return None,
+    };
+    Some(header + 1 + after_type)
+}
+
+/// Overwrites the msg_id of the message encoded at the start of `bytes`
+/// without re-encoding it.
+pub fn patch_msg_id(bytes: &mut [u8], new_id: u16) -> byte::Result<()> {
+    let offset = &mut 0;
+    read_len(bytes, offset)?;
+    let extended_len = *offset == 3;
+    let msg_type: u8 = bytes.read(offset)?;
+    let offset = &mut msg_id_offset(msg_type, extended_len).ok_or(byte::Error::BadInput {
+        err: "Message type has no msg_id",
+    })?;
+    bytes.write_with(offset, new_id, byte::ctx::BE)
+}
+
+/// Accumulates encoded messages for an outgoing datagram of at most `N`
+/// bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MessageBuf<const N: usize>(heapless::Vec<u8, N>);
+
+impl<const N: usize> MessageBuf<N> {
+    pub fn new() -> Self {
+        Self(heapless::Vec::new())
+    }
+
+    /// Encodes `msg` after the messages already in the buffer. On error the
+    /// buffer is left unchanged.
+    pub fn try_push(&mut self, msg: &Message) -> byte::Result<()> {
+        let start = self.0.len();
+        self.0.resize_default(N).ok();
+        let offset = &mut start.clone();
+        let res = self.0.write(offset, msg);
+        self.0.truncate(if res.is_ok() { *offset } else { start });
+        res
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// A reusable buffer of `N` bytes holding one encoded message at a time.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Encoder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Encoder<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0u8; N],
+            len: 0,
+        }
+    }
+
+    /// Encodes `msg` over whatever the buffer held before and returns the
+    /// encoded bytes. On error the buffer is left empty.
+    pub fn encode(&mut self, msg: &Message) -> byte::Result<&[u8]> {
+        self.len = 0;
+        let offset = &mut 0;
+        self.buf.write(offset, msg)?;
+        self.len = *offset;
+        Ok(&self.buf[..self.len])
+    }
+
+    /// The most recently encoded message.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Default for Encoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SearchGw {
+    pub radius: u8,
+}
+
+impl SearchGw {
+    /// A SEARCHGW with radius 0, reaching only the local network.
+    pub fn broadcast() -> Self {
+        Self { radius: 0 }
+    }
+}
+
+impl MsgType for SearchGw {
+    const MSG_TYPE: u8 = 0x01;
+}
+
+impl TryWrite for &SearchGw {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 3u8)?; // len
+        bytes.write(offset, 0x01u8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(SearchGw);
+
+impl TryRead<'_> for SearchGw {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len: u8 = bytes.read(offset)?;
+        check_len(bytes, len as usize)?;
+        *offset += 1; // msg type
+        Ok((Self::read_body(&bytes[..len as usize], offset)?, *offset))
+    }
+}
+
+impl MessageBody for SearchGw {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.radius)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        Ok(SearchGw {
+            radius: bytes.read(offset)?,
+        })
+    }
+}
+
+impl WireFormat for SearchGw {
+    fn encoded_len(&self) -> usize {
+        3
+    }
+
+    wire_format_codec!();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GwInfo {
+    pub gw_id: u8,
+    pub gw_add: Option<GwAddress>,
+}
+
+impl GwInfo {
+    /// Builds a GWINFO from raw field values. An empty `gw_add` means no
+    /// address, as on the wire. Fails only if `gw_add` is longer than 32
+    /// bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(gw_id: u8, gw_add: &[u8]) -> Result<GwInfo, ()> {
+        Ok(GwInfo {
+            gw_id,
+            gw_add: match gw_add {
+                [] => None,
+                add => Some(GwAddress::try_from(add).map_err(|_e| ())?),
+            },
+        })
+    }
+
+    /// A GWINFO sent by a gateway about itself carries no address.
+    pub fn is_from_gateway(&self) -> bool {
+        self.gw_add.is_none()
+    }
+
+    /// A GWINFO sent by a client on behalf of a known gateway carries the
+    /// gateway's address.
+    pub fn is_from_client(&self) -> bool {
+        self.gw_add.is_some()
+    }
+}
+
+impl MsgType for GwInfo {
+    const MSG_TYPE: u8 = 0x02;
+}
+
+impl TryWrite for &GwInfo {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        let len = 3 + self.gw_add.as_ref().map_or(0, |add| add.len()) as u8;
+        bytes.write(offset, len)?;
+        bytes.write(offset, 0x02u8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(GwInfo);
+
+impl_try_read_via_ref!(GwInfo, GwInfoRef);
+
+impl MessageBody for GwInfo {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.gw_id)?;
+        if let Some(gw_add) = &self.gw_add {
+            bytes.write(offset, gw_add)?;
+        }
+        Ok(())
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        GwInfoRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for GwInfo {
+    fn encoded_len(&self) -> usize {
+        3 + self.gw_add.as_ref().map_or(0, |add| add.len())
+    }
+
+    wire_format_codec!();
+}
+
+/// Up to `N` bytes stored inline, so types holding it stay `Copy`. Compares,
+/// hashes and prints as the byte slice it holds.
+#[derive(Clone, Copy)]
+pub struct ByteBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> ByteBuf<N> {
+    pub const fn new() -> Self {
+        ByteBuf {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Copies `bytes` in, failing if there are more than `N`.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() > N {
+            return Err(());
+        }
+        let mut buf = [0; N];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(ByteBuf {
+            buf,
+            len: bytes.len(),
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Default for ByteBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for ByteBuf<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<const N: usize> DerefMut for ByteBuf<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> fmt::Debug for ByteBuf<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<const N: usize> defmt::Format for ByteBuf<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=[u8]}", self.as_slice())
+    }
+}
+
+impl<const N: usize> PartialEq for ByteBuf<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<const N: usize> Eq for ByteBuf<N> {}
+
+impl<const N: usize> core::hash::Hash for ByteBuf<N> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
+    }
+}
+
+impl<const N: usize> PartialOrd for ByteBuf<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize> Ord for ByteBuf<N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GwAddress(ByteBuf<{ GwAddress::MAX_LEN }>);
+
+impl GwAddress {
+    pub const MAX_LEN: usize = 32;
+
+    pub fn new() -> Self {
+        Self(ByteBuf::new())
+    }
+}
+
+impl TryFrom<&[u8]> for GwAddress {
+    type Error = byte::Error;
+
+    fn try_from(s: &[u8]) -> byte::Result<Self> {
+        ByteBuf::from_slice(s)
+            .map(GwAddress)
+            .map_err(|_e| byte::Error::BadInput {
+                err: "gw_add longer than 32 bytes",
+            })
+    }
+}
+
+impl Deref for GwAddress {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for GwAddress {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl TryWrite for &GwAddress {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, self.0.as_slice())?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(GwAddress);
+
+impl TryRead<'_, usize> for GwAddress {
+    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let add =
+            GwAddress::try_from(bytes.read_with::<&[u8]>(offset, byte::ctx::Bytes::Len(len))?)?;
+        Ok((add, *offset))
+    }
+}
+
+/// A CONNECT whose client id holds up to `ID` bytes. Gateways enforcing the
+/// spec's limit can use `Connect<23>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Connect<const ID: usize = 64> {
+    pub flags: Flags,
+    pub duration: u16,
+    pub client_id: ClientId<ID>,
+}
+
+impl Connect {
+    /// Builds a CONNECT from raw field values without checking them. Fails
+    /// only if `client_id` is longer than 64 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(flags: u8, duration: u16, client_id: &str) -> Result<Connect, ()> {
+        Ok(Connect {
+            flags: Flags::from_bits(flags),
+            duration,
+            client_id: ClientId::try_new(client_id)?,
+        })
+    }
+
+    /// A CONNECT that resumes an existing session: clean_session is clear,
+    /// so the gateway keeps the client's subscriptions and will, and no new
+    /// will is sent.
+    pub fn resume(client_id: &str, duration: u16) -> Self {
+        let mut flags = Flags::default();
+        flags.set_clean_session(false);
+        flags.set_will(false);
+        Connect {
+            flags,
+            duration,
+            client_id: client_id.into(),
+        }
+    }
+
+    /// A CONNECT that starts a new session, discarding any state the gateway
+    /// kept. With `will` set, the gateway goes on to ask for the will topic
+    /// and message.
+    pub fn fresh(client_id: &str, duration: u16, will: bool) -> Self {
+        let mut flags = Flags::default();
+        flags.set_clean_session(true);
+        flags.set_will(will);
+        Connect {
+            flags,
+            duration,
+            client_id: client_id.into(),
+        }
+    }
+}
+
+impl<const ID: usize> Connect<ID> {
+    /// The keepalive duration as a [`Duration`](core::time::Duration).
+    pub fn keepalive_duration(&self) -> core::time::Duration {
+        core::time::Duration::from_secs(self.duration as u64)
+    }
+
+    /// Sets the keepalive duration in whole seconds, clamped to the 65535
+    /// seconds the field can hold.
+    pub fn with_keepalive(mut self, keepalive: core::time::Duration) -> Self {
+        self.duration = keepalive.as_secs().min(u16::MAX as u64) as u16;
+        self
+    }
+
+    /// Rejects a keepalive duration above `max` seconds.
+    pub fn validate_duration(&self, max: u16) -> Result<(), DecodeError> {
+        if self.duration > max {
+            return Err(DecodeError::DurationTooLong {
+                duration: self.duration,
+                max,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<const ID: usize> MsgType for Connect<ID> {
+    const MSG_TYPE: u8 = 0x04;
+}
+
+impl<const ID: usize> TryWrite for &Connect<ID> {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        let len = 6 + self.client_id.len();
+        if len > 255 {
+            return Err(byte::Error::BadInput {
+                err: "Connect longer than 255 bytes",
+            });
+        }
+        bytes.write(offset, len as u8)?;
+        bytes.write(offset, 0x04u8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(<const ID: usize> Connect<ID>);
+
+impl_try_read_via_ref!(<const ID: usize> Connect<ID>, ConnectRef);
+
+impl<const ID: usize> MessageBody for Connect<ID> {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.flags)?;
+        bytes.write(offset, 0x01u8)?; // protocol id
+        bytes.write_with(offset, self.duration, byte::ctx::BE)?;
+        bytes.write(offset, self.client_id.as_str())
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        ConnectRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl<const ID: usize> WireFormat for Connect<ID> {
+    fn encoded_len(&self) -> usize {
+        6 + self.client_id.len()
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, ctx: ParseCtx) -> Result<(), DecodeError> {
+        // Only the will and clean_session bits mean anything in a CONNECT.
+        if self.flags.0 & !(Flags::WILL | Flags::CLEAN_SESSION) != 0 {
+            return Err(DecodeError::InvalidFlags(self.flags.0));
+        }
+        check_client_id(&self.client_id, ctx)
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialOrd, Ord, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClientId<const N: usize = 64>(heapless::String<N>);
+
+impl ClientId {
+    /// Longest client id the MQTT-SN spec requires gateways to accept.
+    pub const SPEC_MAX_LEN: usize = 23;
+
+    pub fn new() -> Self {
+        Self(String::new())
+    }
+}
+
+impl<const N: usize> ClientId<N> {
+    /// Parse error for an id over capacity. The default capacity keeps the
+    /// message it had before `ClientId` took a capacity parameter.
+    const TOO_LONG: &'static str = if N == 64 {
+        "client_id longer than 64 bytes"
+    } else {
+        "client_id longer than capacity"
+    };
+
+    /// Like `From<&str>`, but fails instead of panicking if `s` is longer
+    /// than `N` bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn try_new(s: &str) -> Result<Self, ()> {
+        let mut id = Self(String::new());
+        id.set(s)?;
+        Ok(id)
+    }
+
+    /// Replaces the client id with `s`, leaving it unchanged if `s` is
+    /// longer than `N` bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn set(&mut self, s: &str) -> Result<(), ()> {
+        if s.len() > self.0.capacity() {
+            return Err(());
+        }
+        self.0.clear();
+        self.0.push_str(s)
+    }
+
+    /// Whether the id is non-empty, printable and at most
+    /// [`ClientId::SPEC_MAX_LEN`] characters.
+    pub fn is_valid(&self) -> bool {
+        self.is_valid_with_max(ClientId::SPEC_MAX_LEN)
+    }
+
+    /// Like [`ClientId::is_valid`] with a custom maximum length in characters.
+    pub fn is_valid_with_max(&self, max_len: usize) -> bool {
+        !self.is_empty() && self.chars().count() <= max_len && !self.chars().any(|c| c.is_control())
+    }
+}
+
+impl From<&str> for ClientId {
+    fn from(s: &str) -> Self {
+        Self(String::from(s))
+    }
+}
+
+impl<const N: usize> Deref for ClientId<N> {
+    type Target = heapless::String<N>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<const N: usize> DerefMut for ClientId<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> TryWrite for &ClientId<N> {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, self.as_str())?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(<const N: usize> ClientId<N>);
+
+impl<const N: usize> TryRead<'_, usize> for ClientId<N> {
+    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let mut s = String::new();
+        s.push_str(bytes.read_with(offset, byte::ctx::Str::Len(len))?)
+            .map_err(|_e| byte::Error::BadInput {
+                err: Self::TOO_LONG,
+            })?;
+        Ok((ClientId(s), *offset))
+    }
+}
+
+/// Iterates over vendor extension TLVs, each a type byte, a length byte and
+/// that many value bytes, yielding `(type, value)`. Stops at a truncated
+/// entry.
+#[derive(Clone, Debug)]
+pub struct TlvIter<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> TlvIter<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// The bytes not yet iterated over. Once iteration stops, this is the
+    /// truncated entry, if any.
+    pub fn remainder(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = &mut 0;
+        let kind: u8 = self.bytes.read(offset).ok()?;
+        let len: u8 = self.bytes.read(offset).ok()?;
+        let value: &[u8] = self
+            .bytes
+            .read_with(offset, byte::ctx::Bytes::Len(len as usize))
+            .ok()?;
+        self.bytes = &self.bytes[*offset..];
+        Some((kind, value))
+    }
+}
+
+/// Most vendor extension bytes kept after the standard fields of a CONNACK
+/// or REGACK.
+pub const MAX_EXTENSION_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnAck {
+    pub code: ReturnCode,
+    /// Vendor extension bytes after the return code, kept so they
+    /// round-trip. Empty for a standard 3-byte CONNACK.
+    pub extra: ByteBuf<MAX_EXTENSION_LEN>,
+}
+
+impl ConnAck {
+    /// Builds a CONNACK from a raw return code and extension bytes without
+    /// checking them. Fails only if `extra` is longer than
+    /// [`MAX_EXTENSION_LEN`].
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(code: u8, extra: &[u8]) -> Result<ConnAck, ()> {
+        Ok(ConnAck {
+            code: ReturnCode::from_u8(code),
+            extra: ByteBuf::from_slice(extra)?,
+        })
+    }
+
+    pub fn extra(&self) -> &[u8] {
+        &self.extra
+    }
+
+    /// Parses [`ConnAck::extra`] as vendor extension TLVs.
+    pub fn trailing_tlvs(&self) -> TlvIter<'_> {
+        TlvIter::new(&self.extra)
+    }
+}
+
+impl fmt::Display for ConnAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CONNACK {}", self.code)
+    }
+}
+
+impl MsgType for ConnAck {
+    const MSG_TYPE: u8 = 0x05;
+}
+
+impl TryWrite for &ConnAck {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 3 + self.extra.len() as u8)?; // len
+        bytes.write(offset, 0x05u8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(ConnAck);
+
+impl_try_read_via_ref!(ConnAck, ConnAckRef);
+
+impl MessageBody for ConnAck {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.code)?;
+        bytes.write(offset, self.extra.as_slice())
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        ConnAckRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for ConnAck {
+    fn encoded_len(&self) -> usize {
+        3 + self.extra.len()
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_return_code(self.code)?;
+        check_extension(3, &self.extra)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WillTopic {
+    pub flags: Flags,
+    pub topic_name: TopicName,
+}
+
+impl WillTopic {
+    /// Builds a WILLTOPIC from raw field values without checking them. Fails
+    /// only if `topic_name` is longer than 256 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(flags: u8, topic_name: &[u8]) -> Result<WillTopic, ()> {
+        Ok(WillTopic {
+            flags: Flags::from_bits(flags),
+            topic_name: TopicName(heapless::Vec::from_slice(topic_name)?),
+        })
+    }
+
+    /// Creates a WILLTOPIC carrying the will's QoS and retain flag. All other
+    /// flag bits are left zero.
+    ///
+    /// An empty `topic` with QoS 0 and no retain is the same as
+    /// [`WillTopic::empty`] and encodes as the will-deletion frame; prefer
+    /// `empty()` when that is the intent.
+    pub fn new(topic: &str, qos: QoS, retain: bool) -> Self {
+        let mut flags = Flags::default();
+        flags.set_qos(qos.into());
+        flags.set_retain(retain);
+        Self {
+            flags,
+            topic_name: TopicName::from(topic),
+        }
+    }
+
+    /// Creates the empty WILLTOPIC a client sends to delete its will.
+    pub fn empty() -> Self {
+        Self {
+            flags: Flags::default(),
+            topic_name: TopicName::new(),
+        }
+    }
+
+    /// Whether this is the empty WILLTOPIC, encoded without flags or topic.
+    pub fn is_empty(&self) -> bool {
+        self.flags == Flags::default() && self.topic_name.is_empty()
+    }
+
+    pub fn qos(&self) -> QoS {
+        self.flags.qos().into()
+    }
+
+    pub fn retain(&self) -> bool {
+        self.flags.retain()
+    }
+}
+
+impl MsgType for WillTopic {
+    const MSG_TYPE: u8 = 0x07;
+}
+
+impl TryWrite for &WillTopic {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        if self.is_empty() {
+            bytes.write(offset, 2u8)?; // len
+            bytes.write(offset, WillTopic::MSG_TYPE)?;
+            return Ok(*offset);
+        }
+        let len = 3 + self.topic_name.wire_len();
+        if len > 0xff {
+            return Err(byte::Error::BadInput {
+                err: "WillTopic len must be <= 255 bytes",
+            });
+        }
+        bytes.write(offset, len as u8)?;
+        bytes.write(offset, WillTopic::MSG_TYPE)?;
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(WillTopic);
+
+impl_try_read_via_ref!(WillTopic, WillTopicRef);
+
+impl MessageBody for WillTopic {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        bytes.write(offset, self.flags)?;
+        bytes.write(offset, &self.topic_name)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        WillTopicRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for WillTopic {
+    fn encoded_len(&self) -> usize {
+        if self.is_empty() {
+            2
+        } else {
+            3 + self.topic_name.wire_len()
+        }
+    }
+
+    wire_format_codec!();
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Register {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub topic_name: TopicName,
+}
+
+impl Register {
+    /// Builds a REGISTER from raw field values without checking them. Fails
+    /// only if `topic_name` is longer than 256 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(topic_id: u16, msg_id: u16, topic_name: &[u8]) -> Result<Register, ()> {
+        Ok(Register {
+            topic_id,
+            msg_id,
+            topic_name: TopicName(heapless::Vec::from_slice(topic_name)?),
+        })
+    }
+}
+
+impl MsgType for Register {
+    const MSG_TYPE: u8 = 0x0a;
+}
+
+impl TryWrite for &Register {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        write_len(bytes, offset, 5 + self.topic_name.wire_len())?;
+        bytes.write(offset, 0x0Au8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(Register);
+
+impl_try_read_via_ref!(Register, RegisterRef);
+
+impl MessageBody for Register {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, &self.topic_name)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        RegisterRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for Register {
+    fn encoded_len(&self) -> usize {
+        len_with_header(5 + self.topic_name.wire_len())
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_msg_id(self.msg_id)
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialOrd, Ord, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TopicName(heapless::Vec<u8, 256>);
+
+impl TopicName {
+    pub fn from(s: &str) -> Self {
+        Self(heapless::Vec::from_slice(s.as_bytes()).unwrap())
+    }
+    pub fn new() -> Self {
+        Self(heapless::Vec::new())
+    }
+
+    /// Returns the topic name as a string, failing if it isn't valid UTF-8.
+    pub fn as_str(&self) -> Result<&str, core::str::Utf8Error> {
+        core::str::from_utf8(&self.0)
+    }
+
+    /// Number of bytes the name takes on the wire, which is what length
+    /// fields count. For non-ASCII names this is more than the number of
+    /// characters.
+    pub fn wire_len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Replaces the topic name with `s`, leaving it unchanged if `s` is
+    /// longer than 256 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn set(&mut self, s: &str) -> Result<(), ()> {
+        self.0 = heapless::Vec::from_slice(s.as_bytes())?;
+        Ok(())
+    }
+
+    /// Matches this topic name against an MQTT topic filter, where `+`
+    /// matches a single level and a trailing `#` matches any remaining
+    /// levels. Wildcards at the first level don't match `$` topics.
+    pub fn matches_filter(&self, filter: &TopicName) -> bool {
+        if self.first() == Some(&b'$') && matches!(filter.first(), Some(b'+' | b'#')) {
+            return false;
+        }
+        let mut levels = self.split(|b| *b == b'/');
+        let mut filter_levels = filter.split(|b| *b == b'/');
+        loop {
+            match (filter_levels.next(), levels.next()) {
+                (Some([b'#']), _) => return filter_levels.next().is_none(),
+                (Some([b'+']), Some(_)) => {}
+                (Some(f), Some(l)) if f == l => {}
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl TopicName {
+    /// The `/`-separated levels of the name or filter. Yields nothing if it
+    /// isn't valid UTF-8.
+    pub fn levels(&self) -> impl Iterator<Item = &str> {
+        self.as_str().ok().into_iter().flat_map(|s| s.split('/'))
+    }
+
+    /// The `n`th level, counting from 0, as yielded by [`TopicName::levels`].
+    pub fn level(&self, n: usize) -> Option<&str> {
+        self.levels().nth(n)
+    }
+
+    /// Whether this is a well-formed topic filter: not empty, with `+` and
+    /// `#` only as whole levels and `#` only as the last level.
+    pub fn is_valid_filter(&self) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let mut levels = self.split(|b| *b == b'/').peekable();
+        while let Some(level) = levels.next() {
+            match level {
+                [b'#'] if levels.peek().is_none() => {}
+                [b'+'] => {}
+                _ if level.contains(&b'#') || level.contains(&b'+') => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+impl PartialEq<str> for TopicName {
+    fn eq(&self, other: &str) -> bool {
+        self.as_slice() == other.as_bytes()
+    }
+}
+
+impl Deref for TopicName {
+    type Target = heapless::Vec<u8, 256>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<&str> for TopicName {
+    fn from(s: &str) -> Self {
+        Self::from(s)
+    }
+}
+
+impl From<&[u8]> for TopicName {
+    fn from(s: &[u8]) -> Self {
+        Self(heapless::Vec::from_slice(s).unwrap())
+    }
+}
+
+/// Gives unchecked access to the bytes; wildcards aren't rejected. The
+/// length field is computed from the contents when encoding, so any length
+/// up to capacity encodes correctly.
+impl DerefMut for TopicName {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl TryWrite for &TopicName {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, self.as_slice())?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(TopicName);
+
+impl TryRead<'_, usize> for TopicName {
+    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let name = heapless::Vec::from_slice(bytes.read_with(offset, byte::ctx::Bytes::Len(len))?)
+            .map_err(|_e| byte::Error::BadInput {
+                err: "topic_name longer than 256 bytes",
+            })?;
+        Ok((TopicName(name), *offset))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegAck {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub code: ReturnCode,
+    /// Vendor extension bytes after the return code, kept so they
+    /// round-trip. Empty for a standard 7-byte REGACK.
+    pub extra: ByteBuf<MAX_EXTENSION_LEN>,
+}
+
+impl RegAck {
+    /// Builds a REGACK from raw field values without checking them. Fails
+    /// only if `extra` is longer than [`MAX_EXTENSION_LEN`].
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(topic_id: u16, msg_id: u16, code: u8, extra: &[u8]) -> Result<RegAck, ()> {
+        Ok(RegAck {
+            topic_id,
+            msg_id,
+            code: ReturnCode::from_u8(code),
+            extra: ByteBuf::from_slice(extra)?,
+        })
+    }
+
+    /// Acknowledges `register` with the assigned `topic_id`, echoing its msg
+    /// id.
+    pub fn reply_to(register: &Register, topic_id: u16, code: ReturnCode) -> RegAck {
+        RegAck {
+            topic_id,
+            msg_id: register.msg_id,
+            code,
+            extra: ByteBuf::new(),
+        }
+    }
+
+    /// Returns `(topic_id, msg_id)` if the REGISTER was accepted, or the
+    /// rejection reason along with them otherwise.
+    pub fn check(&self) -> Result<(u16, u16), (RejectedReason, u16, u16)> {
+        match self.code {
+            ReturnCode::Accepted => Ok((self.topic_id, self.msg_id)),
+            ReturnCode::Rejected(reason) => Err((reason, self.topic_id, self.msg_id)),
+        }
+    }
+
+    /// Parses [`RegAck::extra`] as vendor extension TLVs.
+    pub fn trailing_tlvs(&self) -> TlvIter<'_> {
+        TlvIter::new(&self.extra)
+    }
+}
+
+impl MsgType for RegAck {
+    const MSG_TYPE: u8 = 0x0b;
+}
+
+impl TryWrite for &RegAck {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 7 + self.extra.len() as u8)?; // len
+        bytes.write(offset, 0xBu8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(RegAck);
+
+impl_try_read_via_ref!(RegAck, RegAckRef);
+
+impl MessageBody for RegAck {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, self.code)?;
+        bytes.write(offset, self.extra.as_slice())
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        RegAckRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for RegAck {
+    fn encoded_len(&self) -> usize {
+        7 + self.extra.len()
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_return_code(self.code)?;
+        check_extension(7, &self.extra)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Publish {
+    pub flags: Flags,
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub data: PublishData,
+}
+
+/// The topic of a PUBLISH, by kind of topic id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TopicRef {
+    Normal(u16),
+    Predefined(u16),
+    ShortName([u8; 2]),
+}
+
+impl Publish {
+    /// Builds a PUBLISH from raw field values without checking them, e.g. as
+    /// decoded by another library. Fails only if `payload` is longer than
+    /// 256 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        payload: &[u8],
+    ) -> Result<Publish, ()> {
+        Ok(Publish {
+            flags: Flags::from_bits(flags),
+            topic_id,
+            msg_id,
+            data: PublishData(heapless::Vec::from_slice(payload)?),
+        })
+    }
+
+    /// Builds a PUBLISH to a normal topic id at `qos`. QoS 0 and -1 carry no
+    /// msg id, so any given is replaced by 0; QoS 1 and 2 need a nonzero one.
+    pub fn new_qos(
+        qos: QoS,
+        topic_id: u16,
+        msg_id: Option<u16>,
+        payload: &[u8],
+    ) -> Result<Publish, EncodeError> {
+        let msg_id = match qos {
+            QoS::AtMostOnce | QoS::MinusOne => 0,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => match msg_id {
+                Some(0) | None => return Err(EncodeError::BadInput("msg_id must be nonzero")),
+                Some(msg_id) => msg_id,
+            },
+        };
+        let mut flags = Flags::default();
+        flags.set_qos(qos.into());
+        Ok(Publish {
+            flags,
+            topic_id,
+            msg_id,
+            data: PublishData(
+                heapless::Vec::from_slice(payload)
+                    .map_err(|_e| EncodeError::BadInput("payload longer than 256 bytes"))?,
+            ),
+        })
+    }
+
+    /// Builds a QoS -1 PUBLISH, which a client may send without connecting.
+    /// Only predefined topic ids and short topic names can be used, and the
+    /// payload must fit in a [`PublishData`].
+    #[allow(clippy::result_unit_err)]
+    pub fn qos_minus_one(topic: TopicRef, payload: &[u8]) -> Result<Publish, ()> {
+        let (topic_id_type, topic_id) = match topic {
+            TopicRef::Predefined(id) => (TopicIdType::Predefined, id),
+            TopicRef::ShortName(name) => (TopicIdType::ShortName, u16::from_be_bytes(name)),
+            TopicRef::Normal(_) => return Err(()),
+        };
+        let mut flags = Flags::default();
+        flags.set_qos(QoS::MinusOne.into());
+        flags.set_topic_id_type(topic_id_type.into());
+        Ok(Publish {
+            flags,
+            topic_id,
+            msg_id: 0,
+            data: PublishData(heapless::Vec::from_slice(payload)?),
+        })
+    }
+
+    /// Builds the QoS 0 PUBLISH that clears the retained message on a topic:
+    /// retain set and an empty payload.
+    pub fn clear_retained(topic_id: u16, topic_id_type: TopicIdType) -> Publish {
+        let mut flags = Flags::default();
+        flags.set_retain(true);
+        flags.set_topic_id_type(topic_id_type.into());
+        Publish {
+            flags,
+            topic_id,
+            msg_id: 0,
+            data: PublishData::new(),
+        }
+    }
+
+    /// Checks the topic against its `topic_id_type`. Topic id 0 is invalid
+    /// as a normal id but fine for predefined ids and short names, where it
+    /// is just two zero bytes. The reserved id type is always rejected.
+    pub fn validate_topic(&self) -> Result<(), DecodeError> {
+        match TopicIdType::from(self.flags.topic_id_type()) {
+            TopicIdType::Normal if self.topic_id == 0 => Err(DecodeError::InvalidTopicId),
+            TopicIdType::Reserved => {
+                Err(DecodeError::InvalidTopicIdType(self.flags.topic_id_type()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses a PUBLISH into `self`, copying the payload into the existing
+    /// [`PublishData`] rather than building a new one. `self` is left as is
+    /// on error. Returns the number of bytes consumed.
+    pub fn decode_into(&mut self, buf: &[u8]) -> Result<usize, DecodeError> {
+        let (publish, len) = PublishRef::try_read(buf, ())?;
+        if publish.data.len() > PublishData::capacity() {
+            return Err(DecodeError::BadInput(PublishData::TOO_LONG));
+        }
+        self.flags = publish.flags;
+        self.topic_id = publish.topic_id;
+        self.msg_id = publish.msg_id;
+        self.data.0.clear();
+        // Cannot fail: the length was checked against the capacity above.
+        self.data.0.extend_from_slice(publish.data).ok();
+        Ok(len)
+    }
+
+    /// Whether the DUP flag marks this as a retransmission.
+    pub fn is_duplicate(&self) -> bool {
+        self.flags.dup()
+    }
+
+    /// Replaces the flags, keeping every other field.
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets only the retain bit.
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.flags.set_retain(retain);
+        self
+    }
+
+    /// Sets only the DUP bit, e.g. before a resend.
+    pub fn with_dup(mut self, dup: bool) -> Self {
+        self.flags.set_dup(dup);
+        self
+    }
+
+    /// Sets only the QoS bits. Unlike [`Publish::new_qos`], the msg id is
+    /// left as is, so moving to QoS 1 or 2 needs a nonzero one set
+    /// separately.
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.flags.set_qos(qos.into());
+        self
+    }
+}
+
+/// Remembers the `(topic_id, msg_id)` of the last `N` PUBLISH messages so
+/// QoS 1 receivers can drop retransmissions.
+#[derive(Clone, Debug, Default)]
+pub struct DedupCache<const N: usize> {
+    seen: heapless::Deque<(u16, u16), N>,
+}
+
+impl<const N: usize> DedupCache<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether `msg` was seen before, recording it if not. The
+    /// oldest entry is forgotten once `N` ids are tracked.
+    pub fn seen(&mut self, msg: &Publish) -> bool {
+        let key = (msg.topic_id, msg.msg_id);
+        if self.seen.iter().any(|k| *k == key) {
+            return true;
+        }
+        if self.seen.is_full() {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(key).ok();
+        false
+    }
+}
+
+impl MsgType for Publish {
+    const MSG_TYPE: u8 = 0x0c;
+}
+
+impl TryWrite for &Publish {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        write_len(bytes, offset, 6 + self.data.len())?;
+        bytes.write(offset, 0x0Cu8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(Publish);
+
+impl_try_read_via_ref!(Publish, PublishRef);
+
+impl MessageBody for Publish {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.flags)?;
+        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, &self.data)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        PublishRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for Publish {
+    fn encoded_len(&self) -> usize {
+        len_with_header(6 + self.data.len())
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_topic_id_type(self.flags)?;
+        match QoS::from(self.flags.qos()) {
+            QoS::AtLeastOnce | QoS::ExactlyOnce => check_msg_id(self.msg_id),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Largest PUBLISH payload that fits in a datagram of `mtu` bytes, limited
+/// by the capacity of [`PublishData`].
+pub fn max_payload_for_mtu(mtu: usize) -> usize {
+    // 7 bytes of header with a 1-byte length field, 9 with the 3-byte one.
+    let payload = mtu.saturating_sub(7);
+    if payload <= 248 {
+        payload
+    } else {
+        (mtu - 9).clamp(248, 256)
+    }
+}
+
+/// Splits `payload` into PUBLISHes that each fit in `mtu` bytes, numbered
+/// with consecutive msg ids from `first_msg_id`. Produces nothing if the
+/// payload is empty or `mtu` leaves no room for payload.
+pub fn fragment_publish(
+    topic_id: u16,
+    flags: Flags,
+    first_msg_id: u16,
+    payload: &[u8],
+    mtu: usize,
+) -> impl Iterator<Item = Publish> + '_ {
+    let chunk_len = max_payload_for_mtu(mtu);
+    payload
+        .chunks(chunk_len.max(1))
+        .filter(move |_| chunk_len > 0)
+        .zip(0u16..)
+        .map(move |(chunk, i)| Publish {
+            flags,
+            topic_id,
+            msg_id: first_msg_id.wrapping_add(i),
+            data: PublishData::from(chunk),
+        })
+}
+
+/// The fixed fields of a PUBLISH, for writing one without building a
+/// [`PublishData`] first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PublishHeader {
+    pub flags: Flags,
+    pub topic_id: u16,
+    pub msg_id: u16,
+}
+
+/// Error returned by [`write_publish_streaming`].
+#[cfg(feature = "embedded-io")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum WriteError<E> {
+    Encode(byte::Error),
+    Io(E),
+}
+
+#[cfg(feature = "embedded-io")]
+impl<E: fmt::Debug> fmt::Display for WriteError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Encode(err) => write!(f, "encode error: {:?}", err),
+            WriteError::Io(err) => write!(f, "io error: {:?}", err),
+        }
+    }
+}
+
+#[cfg(all(feature = "embedded-io", feature = "std"))]
+impl<E: fmt::Debug> std::error::Error for WriteError<E> {}
+
+#[cfg(feature = "embedded-io")]
+impl<E> From<byte::Error> for WriteError<E> {
+    fn from(err: byte::Error) -> Self {
+        WriteError::Encode(err)
+    }
+}
+
+/// Writes a PUBLISH to `w`, streaming `payload` directly after the header
+/// instead of copying it into a [`PublishData`]. Payloads too large for a
+/// 1-byte length field use the 3-byte form. Returns the bytes written.
+#[cfg(feature = "embedded-io")]
+pub fn write_publish_streaming<W: embedded_io::Write>(
+    header: &PublishHeader,
+    payload: &[u8],
+    w: &mut W,
+) -> Result<usize, WriteError<W::Error>> {
+    let mut buf = [0u8; 9];
+    let offset = &mut 0;
+    write_len(&mut buf, offset, 6 + payload.len())?;
+    buf.write(offset, 0x0Cu8)?; // msg type
+    buf.write(offset, header.flags)?;
+    buf.write_with(offset, header.topic_id, byte::ctx::BE)?;
+    buf.write_with(offset, header.msg_id, byte::ctx::BE)?;
+    w.write_all(&buf[..*offset]).map_err(WriteError::Io)?;
+    w.write_all(payload).map_err(WriteError::Io)?;
+    Ok(*offset + payload.len())
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialOrd, Ord, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PublishData(heapless::Vec<u8, 256>);
+
+impl PublishData {
+    /// Parse error for a payload over capacity.
+    const TOO_LONG: &'static str = "data longer than 256 bytes";
+
+    pub fn new() -> Self {
+        Self(heapless::Vec::new())
+    }
+
+    /// Largest payload a PUBLISH can carry.
+    pub const fn capacity() -> usize {
+        256
+    }
+
+    /// Bytes that can still be appended.
+    pub fn remaining(&self) -> usize {
+        Self::capacity() - self.0.len()
+    }
+
+    /// Appends `data`, or leaves the payload unchanged if it doesn't fit.
+    #[allow(clippy::result_unit_err)]
+    pub fn try_extend_from_slice(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.0.extend_from_slice(data)
+    }
+
+    /// Returns the payload as a string, failing if it isn't valid UTF-8.
+    pub fn as_str(&self) -> Result<&str, core::str::Utf8Error> {
+        core::str::from_utf8(&self.0)
+    }
+
+    /// Decodes a standard, padded base64 string into a payload.
+    #[cfg(feature = "base64")]
+    pub fn from_base64(s: &str) -> Result<Self, DecodeError> {
+        use base64::Engine;
+        // The decoder wants room for its length estimate, which can be up to
+        // two bytes over.
+        let mut buf = [0u8; 258];
+        let len = base64::engine::general_purpose::STANDARD
+            .decode_slice(s, &mut buf)
+            .map_err(|e| match e {
+                base64::DecodeSliceError::OutputSliceTooSmall => {
+                    DecodeError::BadInput("payload longer than 256 bytes")
+                }
+                base64::DecodeSliceError::DecodeError(_) => DecodeError::BadInput("invalid base64"),
+            })?;
+        heapless::Vec::from_slice(&buf[..len])
+            .map(Self)
+            .map_err(|_e| DecodeError::BadInput("payload longer than 256 bytes"))
+    }
+
+    /// Encodes the payload as standard, padded base64.
+    #[cfg(feature = "base64")]
+    pub fn to_base64<const N: usize>(&self) -> Result<String<N>, EncodeError> {
+        use base64::Engine;
+        let needed = base64::encoded_len(self.len(), true).unwrap_or(usize::MAX);
+        if needed > N {
+            return Err(EncodeError::BufferTooSmall {
+                needed,
+                available: N,
+            });
+        }
+        let mut buf = [0u8; N];
+        let len = base64::engine::general_purpose::STANDARD
+            .encode_slice(&self.0, &mut buf)
+            .map_err(|_e| EncodeError::BadInput("base64 output too long"))?;
+        let mut s = String::new();
+        // base64 output is always ASCII and fits as checked above.
+        s.push_str(core::str::from_utf8(&buf[..len]).unwrap())
+            .unwrap();
+        Ok(s)
+    }
+}
+
+impl From<&str> for PublishData {
+    fn from(s: &str) -> Self {
+        Self(heapless::Vec::from_slice(s.as_bytes()).unwrap())
+    }
+}
+
+impl From<&[u8]> for PublishData {
+    fn from(s: &[u8]) -> Self {
+        Self(heapless::Vec::from_slice(s).unwrap())
+    }
+}
+
+impl Deref for PublishData {
+    type Target = heapless::Vec<u8, 256>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PublishData {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for PublishData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryWrite for &PublishData {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, self.as_slice())?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(PublishData);
+
+impl TryRead<'_, usize> for PublishData {
+    fn try_read(bytes: &[u8], len: usize) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let data = heapless::Vec::from_slice(bytes.read_with(offset, byte::ctx::Bytes::Len(len))?)
+            .map_err(|_e| byte::Error::BadInput {
+                err: Self::TOO_LONG,
+            })?;
+        Ok((PublishData(data), *offset))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PubAck {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub code: ReturnCode,
+}
+
+impl PubAck {
+    /// Acknowledges `publish`, echoing its topic id and msg id.
+    pub fn reply_to(publish: &Publish, code: ReturnCode) -> PubAck {
+        PubAck {
+            topic_id: publish.topic_id,
+            msg_id: publish.msg_id,
+            code,
+        }
+    }
+
+    /// Returns `(topic_id, msg_id)` if the PUBLISH was accepted, or the
+    /// rejection reason along with them otherwise.
+    pub fn check(&self) -> Result<(u16, u16), (RejectedReason, u16, u16)> {
+        match self.code {
+            ReturnCode::Accepted => Ok((self.topic_id, self.msg_id)),
+            ReturnCode::Rejected(reason) => Err((reason, self.topic_id, self.msg_id)),
+        }
+    }
+}
+
+impl MsgType for PubAck {
+    const MSG_TYPE: u8 = 0x0d;
+}
+
+impl TryWrite for &PubAck {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 7u8)?; // len
+        bytes.write(offset, 0x0Du8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(PubAck);
+
+impl TryRead<'_> for PubAck {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let _len: u8 = bytes.read(offset)?;
+        *offset += 1; // msg type
+        Ok((Self::read_body(bytes, offset)?, *offset))
+    }
+}
+
+impl MessageBody for PubAck {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, self.code)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        Ok(PubAck {
+            topic_id: bytes.read_with(offset, byte::ctx::BE)?,
+            msg_id: bytes.read_with(offset, byte::ctx::BE)?,
+            code: bytes.read(offset)?,
+        })
+    }
+}
+
+impl WireFormat for PubAck {
+    fn encoded_len(&self) -> usize {
+        7
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_return_code(self.code)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[allow(clippy::large_enum_variant)]
+pub enum TopicNameOrId {
+    Name(TopicName),
+    Id(u16),
+}
+
+impl TopicNameOrId {
+    fn encoded_len(&self) -> usize {
+        match self {
+            TopicNameOrId::Id(_) => 2,
+            TopicNameOrId::Name(name) => name.wire_len(),
+        }
+    }
+
+    /// Interprets a raw topic field as the wire does: a name for a normal
+    /// topic id type, otherwise a 2-byte big-endian id.
+    fn from_parts(flags: Flags, topic: &[u8]) -> Result<TopicNameOrId, ()> {
+        if flags.topic_id_type() == 0 {
+            return Ok(TopicNameOrId::Name(TopicName(heapless::Vec::from_slice(
+                topic,
+            )?)));
+        }
+        match topic {
+            [hi, lo] => Ok(TopicNameOrId::Id(u16::from_be_bytes([*hi, *lo]))),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryWrite for &TopicNameOrId {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        match self {
+            TopicNameOrId::Id(id) => bytes.write_with(offset, *id, byte::ctx::BE)?,
+            TopicNameOrId::Name(name) => bytes.write(offset, name)?,
+        }
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(TopicNameOrId);
+
+impl TryRead<'_, (Flags, usize)> for TopicNameOrId {
+    fn try_read(bytes: &[u8], ctx: (Flags, usize)) -> byte::Result<(Self, usize)> {
+        let (topic, len) = TopicNameOrIdRef::try_read(bytes, ctx)?;
+        Ok((topic.try_into()?, len))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Subscribe {
+    pub flags: Flags,
+    pub msg_id: u16,
+    pub topic: TopicNameOrId,
+}
+
+impl Subscribe {
+    /// Builds a SUBSCRIBE from raw field values without checking them.
+    /// `topic` is a topic filter for a normal topic id type, otherwise a
+    /// 2-byte big-endian id. Fails if `topic` doesn't fit that form.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(flags: u8, msg_id: u16, topic: &[u8]) -> Result<Subscribe, ()> {
+        let flags = Flags::from_bits(flags);
+        Ok(Subscribe {
+            flags,
+            msg_id,
+            topic: TopicNameOrId::from_parts(flags, topic)?,
+        })
+    }
+}
+
+impl MsgType for Subscribe {
+    const MSG_TYPE: u8 = 0x12;
+}
+
+impl TryWrite for &Subscribe {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        write_len(bytes, offset, 4 + self.topic.encoded_len())?;
+        bytes.write(offset, Subscribe::MSG_TYPE)?;
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(Subscribe);
+
+impl_try_read_via_ref!(Subscribe, SubscribeRef);
+
+impl MessageBody for Subscribe {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.flags)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, &self.topic)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        SubscribeRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for Subscribe {
+    fn encoded_len(&self) -> usize {
+        len_with_header(4 + self.topic.encoded_len())
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        if let TopicNameOrId::Name(filter) = &self.topic {
+            if !filter.is_valid_filter() {
+                return Err(DecodeError::InvalidTopicFilter);
+            }
+        }
+        check_topic_id_type(self.flags)?;
+        check_msg_id(self.msg_id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubAck {
+    pub flags: Flags,
+    pub msg_id: u16,
+    pub topic_id: u16,
+    pub code: ReturnCode,
+}
+
+impl MsgType for SubAck {
+    const MSG_TYPE: u8 = 0x13;
+}
+
+impl SubAck {
+    /// The QoS the gateway granted for the subscription.
+    pub fn granted_qos(&self) -> QoS {
+        QoS::from(self.flags.qos())
+    }
+
+    /// Whether the gateway granted a lower QoS than `requested`.
+    pub fn is_downgrade_from(&self, requested: QoS) -> bool {
+        // QoS -1 is the weakest delivery guarantee, below QoS 0.
+        fn level(qos: QoS) -> i8 {
+            match qos {
+                QoS::MinusOne => -1,
+                qos => u8::from(qos) as i8,
+            }
+        }
+        level(self.granted_qos()) < level(requested)
+    }
+}
+
+impl TryWrite for &SubAck {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 8u8)?; // len
+        bytes.write(offset, SubAck::MSG_TYPE)?;
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(SubAck);
+
+impl TryRead<'_> for SubAck {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let _len: u8 = bytes.read(offset)?;
+        *offset += 1; // msg type
+        Ok((Self::read_body(bytes, offset)?, *offset))
+    }
+}
+
+impl MessageBody for SubAck {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.flags)?;
+        bytes.write_with(offset, self.topic_id, byte::ctx::BE)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, self.code)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        Ok(Self {
+            flags: bytes.read(offset)?,
+            topic_id: bytes.read_with(offset, byte::ctx::BE)?,
+            msg_id: bytes.read_with(offset, byte::ctx::BE)?,
+            code: bytes.read(offset)?,
+        })
+    }
+}
+
+impl WireFormat for SubAck {
+    fn encoded_len(&self) -> usize {
+        8
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_return_code(self.code)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Unsubscribe {
+    pub flags: Flags,
+    pub msg_id: u16,
+    pub topic: TopicNameOrId,
+}
+
+impl Unsubscribe {
+    /// Builds an UNSUBSCRIBE from raw field values, reading `topic` as
+    /// [`Subscribe::from_parts`] does.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(flags: u8, msg_id: u16, topic: &[u8]) -> Result<Unsubscribe, ()> {
+        let flags = Flags::from_bits(flags);
+        Ok(Unsubscribe {
+            flags,
+            msg_id,
+            topic: TopicNameOrId::from_parts(flags, topic)?,
+        })
+    }
+}
+
+impl MsgType for Unsubscribe {
+    const MSG_TYPE: u8 = 0x14;
+}
+
+impl TryWrite for &Unsubscribe {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        write_len(bytes, offset, 4 + self.topic.encoded_len())?;
+        bytes.write(offset, Unsubscribe::MSG_TYPE)?;
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(Unsubscribe);
+
+impl_try_read_via_ref!(Unsubscribe, UnsubscribeRef);
+
+impl MessageBody for Unsubscribe {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write(offset, self.flags)?;
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, &self.topic)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        UnsubscribeRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for Unsubscribe {
+    fn encoded_len(&self) -> usize {
+        len_with_header(4 + self.topic.encoded_len())
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        if let TopicNameOrId::Name(filter) = &self.topic {
+            if !filter.is_valid_filter() {
+                return Err(DecodeError::InvalidTopicFilter);
+            }
+        }
+        check_msg_id(self.msg_id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UnsubAck {
+    pub msg_id: u16,
+    pub code: ReturnCode,
+}
+
+impl MsgType for UnsubAck {
+    const MSG_TYPE: u8 = 0x15;
+}
+
+impl TryWrite for &UnsubAck {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 5u8)?; // len
+        bytes.write(offset, UnsubAck::MSG_TYPE)?;
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(UnsubAck);
+
+impl TryRead<'_> for UnsubAck {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let _len: u8 = bytes.read(offset)?;
+        *offset += 1; // msg type
+        Ok((Self::read_body(bytes, offset)?, *offset))
+    }
+}
+
+impl MessageBody for UnsubAck {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        bytes.write_with(offset, self.msg_id, byte::ctx::BE)?;
+        bytes.write(offset, self.code)
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        Ok(Self {
+            msg_id: bytes.read_with(offset, byte::ctx::BE)?,
+            code: bytes.read(offset)?,
+        })
+    }
+}
+
+impl WireFormat for UnsubAck {
+    fn encoded_len(&self) -> usize {
+        5
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, _ctx: ParseCtx) -> Result<(), DecodeError> {
+        check_return_code(self.code)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingReq {
+    /// Set by a sleeping client checking for buffered messages; `None` for
+    /// a plain keepalive.
+    pub client_id: Option<ClientId>,
+}
+
+impl PingReq {
+    /// Builds a PINGREQ from a raw client id. An empty `client_id` means
+    /// none, as on the wire. Fails only if it is longer than 64 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(client_id: &str) -> Result<PingReq, ()> {
+        Ok(PingReq {
+            client_id: match client_id {
+                "" => None,
+                id => Some(ClientId::try_new(id)?),
+            },
+        })
+    }
+}
+
+impl MsgType for PingReq {
+    const MSG_TYPE: u8 = 0x16;
+}
+
+impl TryWrite for &PingReq {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        let len = 2 + self.client_id.as_ref().map_or(0, |id| id.len()) as u8;
+        bytes.write(offset, len)?;
+        bytes.write(offset, 0x16u8)?; // msg type
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(PingReq);
+
+impl_try_read_via_ref!(PingReq, PingReqRef);
+
+impl MessageBody for PingReq {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        if let Some(client_id) = &self.client_id {
+            bytes.write(offset, client_id.as_str())?;
+        }
+        Ok(())
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        PingReqRef::read_body(bytes, offset)?.try_into()
+    }
+}
+
+impl WireFormat for PingReq {
+    fn encoded_len(&self) -> usize {
+        2 + self.client_id.as_ref().map_or(0, |id| id.len())
+    }
+
+    wire_format_codec!();
+
+    fn validate_with(&self, ctx: ParseCtx) -> Result<(), DecodeError> {
+        match &self.client_id {
+            Some(client_id) => check_client_id(client_id, ctx),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingResp {}
+
+impl MsgType for PingResp {
+    const MSG_TYPE: u8 = 0x17;
+}
+
+impl TryWrite for &PingResp {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write(offset, 2u8)?; // len
+        bytes.write(offset, 0x17u8)?; // msg type
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(PingResp);
+
+impl TryRead<'_> for PingResp {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len: u8 = bytes.read(offset)?;
+        check_len(bytes, len as usize)?;
+        *offset += 1; // msg type
+        Ok((PingResp {}, *offset))
+    }
+}
+
+impl MessageBody for PingResp {
+    fn write_body(&self, _bytes: &mut [u8], _offset: &mut usize) -> byte::Result<()> {
+        Ok(())
+    }
+
+    fn read_body(_bytes: &[u8], _offset: &mut usize) -> byte::Result<Self> {
+        Ok(PingResp {})
+    }
+}
+
+impl WireFormat for PingResp {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    wire_format_codec!();
+}
+
+/// A DISCONNECT. With a duration, the client is going to sleep for that many
+/// seconds rather than ending its session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Disconnect {
+    pub duration: Option<u16>,
+}
+
+impl MsgType for Disconnect {
+    const MSG_TYPE: u8 = 0x18;
+}
+
+impl TryWrite for &Disconnect {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        let len = if self.duration.is_some() { 4u8 } else { 2u8 };
+        bytes.write(offset, len)?;
+        bytes.write(offset, Disconnect::MSG_TYPE)?;
+        self.write_body(bytes, offset)?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(Disconnect);
+
+impl TryRead<'_> for Disconnect {
+    fn try_read(bytes: &[u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let len: u8 = bytes.read(offset)?;
+        check_len(bytes, len as usize)?;
+        *offset += 1; // msg type
+        Ok((Self::read_body(&bytes[..len as usize], offset)?, *offset))
+    }
+}
+
+impl MessageBody for Disconnect {
+    fn write_body(&self, bytes: &mut [u8], offset: &mut usize) -> byte::Result<()> {
+        match self.duration {
+            Some(duration) => bytes.write_with(offset, duration, byte::ctx::BE),
+            None => Ok(()),
+        }
+    }
+
+    fn read_body(bytes: &[u8], offset: &mut usize) -> byte::Result<Self> {
+        let duration = match bytes.len().checked_sub(*offset) {
+            Some(0) => None,
+            Some(2) => Some(bytes.read_with(offset, byte::ctx::BE)?),
+            _ => {
+                return Err(byte::Error::BadInput {
+                    err: "Disconnect len must be 2 or 4 bytes",
+                })
+            }
+        };
+        Ok(Disconnect { duration })
+    }
+}
+
+impl WireFormat for Disconnect {
+    fn encoded_len(&self) -> usize {
+        if self.duration.is_some() {
+            4
+        } else {
+            2
+        }
+    }
+
+    wire_format_codec!();
+}
+
+/// A message with a type code reserved by the spec, kept as raw bytes so
+/// newer peers' messages can be skipped or passed through.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Unknown {
+    pub msg_type: u8,
+    pub body: heapless::Vec<u8, 256>,
+}
+
+impl Unknown {
+    /// Builds a message of a reserved type from its raw body. Fails if
+    /// `msg_type` is assigned to a message this crate parses, or if `body`
+    /// is longer than 256 bytes.
+    #[allow(clippy::result_unit_err)]
+    pub fn from_parts(msg_type: u8, body: &[u8]) -> Result<Unknown, ()> {
+        if !is_reserved_type(msg_type) {
+            return Err(());
+        }
+        Ok(Unknown {
+            msg_type,
+            body: heapless::Vec::from_slice(body)?,
+        })
+    }
+}
+
+impl TryWrite for &Unknown {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        write_len(bytes, offset, 1 + self.body.len())?;
+        bytes.write(offset, self.msg_type)?;
+        bytes.write(offset, self.body.as_slice())?;
+        Ok(*offset)
+    }
+}
+
+impl_try_write_by_value!(Unknown);
+
+impl_try_read_via_ref!(Unknown, UnknownRef);
+
+impl WireFormat for Unknown {
+    fn encoded_len(&self) -> usize {
+        len_with_header(1 + self.body.len())
+    }
+
+    wire_format_codec!(encode);
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let msg_type = read_header(buf)?;
+        if !is_reserved_type(msg_type) {
+            return Err(DecodeError::UnexpectedMessageType(msg_type));
+        }
+        decode_with(buf)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_flags_with(u: &mut arbitrary::Unstructured<'_>, mask: u8) -> arbitrary::Result<Flags> {
+    Ok(Flags(u.arbitrary::<u8>()? & mask))
+}
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_bytes<const N: usize>(
+    u: &mut arbitrary::Unstructured<'_>,
+    max_len: usize,
+) -> arbitrary::Result<heapless::Vec<u8, N>> {
+    let len = u.int_in_range(0..=max_len.min(N))?;
+    Ok(heapless::Vec::from_slice(u.bytes(len)?).unwrap())
+}
+
+/// Generates a string of `alphabet` characters, at least `min_len` long.
+#[cfg(feature = "arbitrary")]
+fn arbitrary_str<const N: usize>(
+    u: &mut arbitrary::Unstructured<'_>,
+    alphabet: &[u8],
+    min_len: usize,
+    max_len: usize,
+) -> arbitrary::Result<heapless::String<N>> {
+    let len = u.int_in_range(min_len..=max_len.min(N))?;
+    let mut s = heapless::String::new();
+    for _ in 0..len {
+        s.push(*u.choose(alphabet)? as char).unwrap();
+    }
+    Ok(s)
+}
+
+#[cfg(feature = "arbitrary")]
+const ARBITRARY_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789/";
+
+/// Generates extension bytes made of complete TLVs, as strict parsing
+/// requires.
+#[cfg(feature = "arbitrary")]
+fn arbitrary_extension(
+    u: &mut arbitrary::Unstructured<'_>,
+) -> arbitrary::Result<ByteBuf<MAX_EXTENSION_LEN>> {
+    let mut extra = [0u8; MAX_EXTENSION_LEN];
+    let mut len = 0;
+    while len + 2 <= MAX_EXTENSION_LEN && u.arbitrary()? {
+        let value_len = u.int_in_range(0..=MAX_EXTENSION_LEN - len - 2)?;
+        extra[len] = u.arbitrary()?;
+        extra[len + 1] = value_len as u8;
+        extra[len + 2..len + 2 + value_len].copy_from_slice(u.bytes(value_len)?);
+        len += 2 + value_len;
+    }
+    Ok(ByteBuf::from_slice(&extra[..len]).unwrap())
+}
+
+/// Generates messages that encode, parse back unchanged and pass strict
+/// validation.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Message {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let nonzero = 1..=u16::MAX;
+        let code =
+            |u: &mut arbitrary::Unstructured<'a>| Ok(ReturnCode::from_u8(u.int_in_range(0..=3)?));
+        Ok(match u.int_in_range(0..=16)? {
+            0 => Message::search_gw(u.arbitrary()?),
+            1 => Message::GwInfo(GwInfo {
+                gw_id: u.arbitrary()?,
+                // An empty address encodes the same as none.
+                gw_add: Some(GwAddress::try_from(&arbitrary_bytes::<32>(u, 32)?[..]).unwrap())
+                    .filter(|add| !add.is_empty()),
+            }),
+            2 => Message::Connect(Connect {
+                flags: arbitrary_flags_with(u, Flags::WILL | Flags::CLEAN_SESSION)?,
+                duration: u.arbitrary()?,
+                client_id: ClientId(arbitrary_str(u, ARBITRARY_ALPHABET, 0, 64)?),
+            }),
+            3 => Message::ConnAck(ConnAck {
+                code: code(u)?,
+                extra: arbitrary_extension(u)?,
+            }),
+            4 => Message::WillTopic(WillTopic {
+                flags: arbitrary_flags_with(u, Flags::QOS_MASK | Flags::RETAIN)?,
+                topic_name: TopicName(arbitrary_bytes(u, 252)?),
+            }),
+            5 => Message::Register(Register {
+                topic_id: u.arbitrary()?,
+                msg_id: u.int_in_range(nonzero)?,
+                topic_name: TopicName(arbitrary_bytes(u, 256)?),
+            }),
+            6 => Message::RegAck(RegAck {
+                topic_id: u.arbitrary()?,
+                msg_id: u.arbitrary()?,
+                code: code(u)?,
+                extra: arbitrary_extension(u)?,
+            }),
+            7 => {
+                let mut flags = u.arbitrary::<u8>().map(Flags)?;
+                // Strict validation rejects the reserved topic id type.
+                if TopicIdType::from(flags.topic_id_type()) == TopicIdType::Reserved {
+                    flags.set_topic_id_type(TopicIdType::Normal.into());
+                }
+                Message::Publish(Publish {
+                    flags,
+                    topic_id: u.arbitrary()?,
+                    msg_id: u.int_in_range(nonzero)?,
+                    data: PublishData(arbitrary_bytes(u, 256)?),
+                })
+            }
+            8 => Message::pub_ack(u.arbitrary()?, u.arbitrary()?, code(u)?),
+            t @ (9 | 10) => {
+                let mut flags = arbitrary_flags_with(u, Flags::DUP | Flags::QOS_MASK)?;
+                let topic = if u.arbitrary()? {
+                    let name = arbitrary_str::<256>(u, ARBITRARY_ALPHABET, 1, 251)?;
+                    TopicNameOrId::Name(TopicName::from(name.as_str()))
+                } else {
+                    flags.set_topic_id_type(TopicIdType::Predefined.into());
+                    TopicNameOrId::Id(u.arbitrary()?)
+                };
+                let msg_id = u.int_in_range(nonzero)?;
+                if t == 9 {
+                    Message::subscribe(flags, msg_id, topic)
+                } else {
+                    Message::unsubscribe(flags, msg_id, topic)
+                }
+            }
+            11 => Message::sub_ack(
+                arbitrary_flags_with(u, Flags::QOS_MASK)?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                code(u)?,
+            ),
+            12 => Message::unsub_ack(u.arbitrary()?, code(u)?),
+            13 => Message::PingReq(PingReq {
+                client_id: if u.arbitrary()? {
+                    Some(ClientId(arbitrary_str(u, ARBITRARY_ALPHABET, 1, 64)?))
+                } else {
+                    None
+                },
+            }),
+            14 => Message::ping_resp(),
+            15 => Message::disconnect(u.arbitrary()?),
+            _ => Message::Unknown(Unknown {
+                msg_type: *u.choose(&[0x03, 0x11, 0x19, 0x1e, 0x80, 0xfd])?,
+                body: arbitrary_bytes(u, 256)?,
+            }),
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+fn shrink_lens(len: usize) -> [usize; 3] {
+    [0, len / 2, len.saturating_sub(1)]
+}
+
+#[cfg(feature = "arbitrary")]
+fn shrink_u16(value: u16) -> [u16; 2] {
+    [0, value / 2]
+}
+
+#[cfg(feature = "arbitrary")]
+impl Message {
+    /// Returns simpler versions of this message for minimizing a failing
+    /// property test input: payloads and names cut short, and numbers and
+    /// flags moved toward zero. Candidates that fail [`Message::validate`]
+    /// are left out.
+    pub fn shrink(&self) -> heapless::Vec<Message, 16> {
+        let mut candidates = heapless::Vec::new();
+        let mut push = |msg: Message| {
+            if msg != *self && msg.validate().is_ok() {
+                candidates.push(msg).ok();
+            }
+        };
+        match self {
+            Message::Publish(msg) => {
+                for len in shrink_lens(msg.data.len()) {
+                    let mut data = msg.data.clone();
+                    data.truncate(len);
+                    push(Message::Publish(Publish {
+                        data,
+                        ..msg.clone()
+                    }));
+                }
+                push(Message::Publish(Publish {
+                    flags: Flags::default(),
+                    ..msg.clone()
+                }));
+                for topic_id in shrink_u16(msg.topic_id) {
+                    push(Message::Publish(Publish {
+                        topic_id,
+                        ..msg.clone()
+                    }));
+                }
+                for msg_id in shrink_u16(msg.msg_id) {
+                    push(Message::Publish(Publish {
+                        msg_id,
+                        ..msg.clone()
+                    }));
+                }
+            }
+            Message::Register(msg) => {
+                for len in shrink_lens(msg.topic_name.len()) {
+                    let mut topic_name = msg.topic_name.clone();
+                    topic_name.truncate(len);
+                    push(Message::Register(Register {
+                        topic_name,
+                        ..msg.clone()
+                    }));
+                }
+                for topic_id in shrink_u16(msg.topic_id) {
+                    push(Message::Register(Register {
+                        topic_id,
+                        ..msg.clone()
+                    }));
+                }
+                for msg_id in shrink_u16(msg.msg_id) {
+                    push(Message::Register(Register {
+                        msg_id,
+                        ..msg.clone()
+                    }));
+                }
+            }
+            Message::Connect(msg) => {
+                for len in shrink_lens(msg.client_id.len()) {
+                    let mut client_id = msg.client_id.clone();
+                    client_id.truncate(len);
+                    push(Message::Connect(Connect {
+                        client_id,
+                        ..msg.clone()
+                    }));
+                }
+                push(Message::Connect(Connect {
+                    flags: Flags::default(),
+                    ..msg.clone()
+                }));
+                for duration in shrink_u16(msg.duration) {
+                    push(Message::Connect(Connect {
+                        duration,
+                        ..msg.clone()
+                    }));
+                }
+            }
+            Message::Unknown(msg) => {
+                for len in shrink_lens(msg.body.len()) {
+                    let mut body = msg.body.clone();
+                    body.truncate(len);
+                    push(Message::Unknown(Unknown {
+                        body,
+                        ..msg.clone()
+                    }));
+                }
+            }
+            _ => {}
+        }
+        candidates
+    }
+}
+
+/// Repeatedly replaces `msg` with the first of its [`Message::shrink`]
+/// candidates that still `fails`, returning the smallest failing message
+/// found.
+#[cfg(feature = "arbitrary")]
+pub fn shrink_message(mut msg: Message, mut fails: impl FnMut(&Message) -> bool) -> Message {
+    'shrink: loop {
+        for candidate in msg.shrink() {
+            if fails(&candidate) {
+                msg = candidate;
+                continue 'shrink;
+            }
+        }
+        return msg;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use assert_hex::*;
+    use std::collections::HashSet;
+    use std::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn forwarded_message_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = ForwardedMessage {
+            ctrl: 0,
+            wireless_node_id: WirelessNodeId::from("test-node"),
+            message: Message::PingResp(PingResp {}),
+        };
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(
+            &bytes[..len],
+            &[12u8, 0xfe, 0x00, b't', b'e', b's', b't', b'-', b'n', b'o', b'd', b'e', 2, 0x17]
+        );
+        let actual: ForwardedMessage = bytes.read(&mut 0).unwrap();
+        assert_eq_hex!(actual, expected);
+    }
+
+    #[test]
+    fn wireless_node_id_try_from() {
+        assert_eq!(
+            WirelessNodeId::try_new("node-1"),
+            Ok(WirelessNodeId::from("node-1"))
+        );
+        assert!(WirelessNodeId::try_new("0123456789abcdef").is_ok());
+        assert_eq!(WirelessNodeId::try_new("0123456789abcdefghij"), Err(()));
+    }
+
+    #[test]
+    fn forwarded_message_node_id_len() {
+        let empty = [0x03u8, 0xfe, 0x00, 0x02, 0x17];
+        assert_eq!(
+            empty.read::<ForwardedMessage>(&mut 0),
+            Ok(ForwardedMessage {
+                ctrl: 0,
+                wireless_node_id: WirelessNodeId::new(),
+                message: Message::ping_resp(),
+            })
+        );
+
+        let mut long = [b'n'; 25];
+        long[..3].copy_from_slice(&[23, 0xfe, 0x00]);
+        long[23..].copy_from_slice(&[0x02, 0x17]);
+        assert_eq!(
+            long.read::<ForwardedMessage>(&mut 0),
+            Err(DecodeError::BadInput("wireless_node_id longer than 16 bytes").into())
+        );
+
+        assert!([0x02u8, 0xfe, 0x02, 0x17]
+            .read::<ForwardedMessage>(&mut 0)
+            .is_err());
+    }
+
+    #[test]
+    fn forwarded_message_hash() {
+        let msg = ForwardedMessage {
+            ctrl: 0,
+            wireless_node_id: WirelessNodeId::from("test-node"),
+            message: Message::PingResp(PingResp {}),
+        };
+        let mut set = HashSet::new();
+        set.insert(MaybeForwardedMessage::from(msg.clone()));
+        set.insert(MaybeForwardedMessage::from(msg));
+        assert_eq!(set.len(), 1);
+    }
+
+    fn wire_roundtrip<T: WireFormat + PartialEq + core::fmt::Debug>(msg: &T) {
+        let mut buf = [0u8; 300];
+        let len = msg.encode(&mut buf).unwrap();
+        assert_eq!(len, msg.encoded_len());
+        let (decoded, decoded_len) = T::decode(&buf[..len]).unwrap();
+        assert_eq!(&decoded, msg);
+        assert_eq!(decoded_len, len);
+        assert_eq!(
+            msg.encode(&mut buf[..len - 1]),
+            Err(EncodeError::BufferTooSmall {
+                needed: len,
+                available: len - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn wire_format_generic() {
+        wire_roundtrip(&SearchGw { radius: 3 });
+        wire_roundtrip(&Publish::new_qos(QoS::AtLeastOnce, 1, Some(7), b"hello").unwrap());
+        wire_roundtrip(&Message::ping_req(Some("client")));
+    }
+
+    #[test]
+    fn wire_format_decode_checks_type() {
+        let reg_ack = [0x07u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x00];
+        assert!(RegAck::decode(&reg_ack).is_ok());
+        assert_eq!(
+            PubAck::decode(&reg_ack),
+            Err(DecodeError::UnexpectedMessageType(0x0b))
+        );
+        assert!(SearchGw::decode(&[0x03, 0x0b, 0x01]).is_err());
+        assert_eq!(
+            Unknown::decode(&[0x02, 0x17]),
+            Err(DecodeError::UnexpectedMessageType(0x17))
+        );
+        assert!(Unknown::decode(&[0x02, 0x03]).is_ok());
+    }
+
+    #[test]
+    fn message_hash_consistent_with_eq() {
+        use std::hash::BuildHasher;
+        // Each RandomState hashes differently, so share one.
+        let state = std::collections::hash_map::RandomState::new();
+        let hash = |msg: &Message| state.hash_one(msg);
+
+        let msgs = [
+            Message::publish(Flags::default(), 1, 2, "abc"),
+            Message::connect("client", 30, true, false),
+            Message::ping_req(Some("client")),
+            Message::reg_ack(1, 2, RejectedReason::Congestion.into()),
+        ];
+        for msg in &msgs {
+            let copy = msg.clone();
+            assert_eq!(msg, &copy);
+            assert_eq!(hash(msg), hash(&copy));
+
+            let mut bytes = [0u8; 32];
+            let mut len = 0usize;
+            bytes.write(&mut len, msg).unwrap();
+            let decoded: Message = bytes.read(&mut 0).unwrap();
+            assert_eq!(msg, &decoded);
+            assert_eq!(hash(msg), hash(&decoded));
+        }
+        let mut set = HashSet::new();
+        for msg in msgs.iter().chain(msgs.iter()) {
+            set.insert(msg.clone());
+        }
+        assert_eq!(set.len(), msgs.len());
+    }
+
+    #[test]
+    fn maybe_forwarded_inner() {
+        let plain = MaybeForwardedMessage::from(Message::ping_resp());
+        assert_eq!(plain.inner(), &Message::ping_resp());
+        assert_eq!(plain.wireless_node_id(), None);
+
+        let forwarded = MaybeForwardedMessage::from(ForwardedMessage {
+            ctrl: 0,
+            wireless_node_id: WirelessNodeId::from("node"),
+            message: Message::ping_resp(),
+        });
+        assert_eq!(forwarded.inner(), &Message::ping_resp());
+        assert_eq!(
+            forwarded.wireless_node_id(),
+            Some(&WirelessNodeId::from("node"))
+        );
+    }
+
+    #[test]
+    fn return_code_encode() {
+        let mut buf = [0u8; 5];
+        let mut offset = 0usize;
+        buf.write(&mut offset, ReturnCode::Accepted).unwrap();
+        buf.write(
+            &mut offset,
+            ReturnCode::Rejected(RejectedReason::Congestion),
+        )
+        .unwrap();
+        buf.write(
+            &mut offset,
+            ReturnCode::Rejected(RejectedReason::InvalidTopicId),
+        )
+        .unwrap();
+        buf.write(
+            &mut offset,
+            ReturnCode::Rejected(RejectedReason::NotSupported),
+        )
+        .unwrap();
+        buf.write(
+            &mut offset,
+            ReturnCode::Rejected(RejectedReason::Reserved(0x12)),
+        )
+        .unwrap();
+        assert_eq_hex!(&buf, &[0x00u8, 0x01u8, 0x02u8, 0x03u8, 0x12u8]);
+    }
+
+    #[test]
+    #[allow(clippy::needless_range_loop)]
+    fn return_code_parse() {
+        let buf = &[0x00u8, 0x01u8, 0x02u8, 0x03u8, 0x12u8];
+        let mut actual = [ReturnCode::Accepted; 5];
+        let mut offset = 0usize;
+        for i in 0..5 {
+            actual[i] = buf.read(&mut offset).unwrap();
+        }
+        assert_eq!(
+            &actual,
+            &[
+                ReturnCode::Accepted,
+                ReturnCode::Rejected(RejectedReason::Congestion),
+                ReturnCode::Rejected(RejectedReason::InvalidTopicId),
+                ReturnCode::Rejected(RejectedReason::NotSupported),
+                ReturnCode::Rejected(RejectedReason::Reserved(0x12)),
+            ]
+        );
+    }
+
+    #[test]
+    fn return_code_parse_strict() {
+        let strict = ParseCtx::strict();
+        let buf = [0x20u8];
+        assert_eq!(
+            ReturnCode::parse(&buf, ParseCtx::default()),
+            Ok((ReturnCode::Rejected(RejectedReason::Reserved(0x20)), 1))
+        );
+        assert_eq!(
+            ReturnCode::parse(&buf, strict),
+            Err(DecodeError::UnknownReturnCode(0x20).into())
+        );
+        assert_eq!(
+            ReturnCode::parse(&[0x03], strict),
+            Ok((ReturnCode::Rejected(RejectedReason::NotSupported), 1))
+        );
+
+        let conn_ack = [0x03u8, 0x05, 0x20];
+        assert_eq!(
+            Message::parse(&conn_ack, ParseCtx::default()),
+            Ok((
+                Message::conn_ack(ReturnCode::Rejected(RejectedReason::Reserved(0x20))),
+                3
+            ))
+        );
+        assert_eq!(
+            Message::parse(&conn_ack, strict),
+            Err(DecodeError::UnknownReturnCode(0x20).into())
+        );
+    }
+
+    #[test]
+    fn rejected_reason_backoff() {
+        assert_eq!(
+            RejectedReason::Congestion.suggested_backoff(),
+            Some(RejectedReason::DEFAULT_RETRY_AFTER_SECS)
+        );
+        assert_eq!(RejectedReason::InvalidTopicId.suggested_backoff(), None);
+        assert_eq!(RejectedReason::NotSupported.suggested_backoff(), None);
+        assert_eq!(RejectedReason::Reserved(0x20).suggested_backoff(), None);
+    }
+
+    #[test]
+    fn rejected_reason_display() {
+        assert_eq!(RejectedReason::Congestion.to_string(), "congestion");
+        assert_eq!(
+            RejectedReason::InvalidTopicId.to_string(),
+            "invalid topic id"
+        );
+        assert_eq!(RejectedReason::NotSupported.to_string(), "not supported");
+        assert_eq!(RejectedReason::Reserved(0x2a).to_string(), "reserved(0x2a)");
+        assert_eq!(ReturnCode::Accepted.to_string(), "accepted");
+        assert_eq!(
+            Message::conn_ack(ReturnCode::Rejected(RejectedReason::Congestion))
+                .as_conn_ack()
+                .unwrap()
+                .to_string(),
+            "CONNACK rejected: congestion"
+        );
+    }
+
+    #[test]
+    fn return_code_numeric() {
+        let codes = [
+            ReturnCode::Accepted,
+            ReturnCode::Rejected(RejectedReason::Congestion),
+            ReturnCode::Rejected(RejectedReason::InvalidTopicId),
+            ReturnCode::Rejected(RejectedReason::NotSupported),
+            ReturnCode::Rejected(RejectedReason::Reserved(0x12)),
+        ];
+        let expected = [0x00u8, 0x01u8, 0x02u8, 0x03u8, 0x12u8];
+        for (code, byte) in codes.iter().zip(expected.iter()) {
+            assert_eq_hex!(code.code(), *byte);
+            assert_eq!(ReturnCode::from_u8(*byte), *code);
+            assert_eq!(ReturnCode::from(*byte), *code);
+        }
+    }
+
+    #[test]
+    fn searchgw_encode_parse() {
+        let bytes = &mut [0u8; 10];
+        let mut len = 0usize;
+        let expected = Message::SearchGw(SearchGw { radius: 5 });
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x03u8, 0x01, 0x05]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn gwinfo_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let gw_info = GwInfo {
+            gw_id: 0x12,
+            gw_add: None,
+        };
+        assert!(gw_info.is_from_gateway());
+        let expected = Message::GwInfo(gw_info);
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x03u8, 0x02, 0x12]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn gwinfo_gw_add_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let gw_info = GwInfo {
+            gw_id: 0x12,
+            gw_add: Some(GwAddress::try_from(&[0xc0u8, 0xa8, 0x00, 0x01][..]).unwrap()),
+        };
+        assert!(gw_info.is_from_client());
+        assert!(!gw_info.is_from_gateway());
+        assert!(GwAddress::try_from(&[0u8; 33][..]).is_err());
+        let expected = Message::GwInfo(gw_info);
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x07u8, 0x02, 0x12, 0xc0, 0xa8, 0x00, 0x01]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn connect_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::Connect(Connect {
+            flags: Flags(0x12),
+            duration: 0x3456,
+            client_id: ClientId::from("test-client"),
+        });
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(
+            &bytes[..len],
+            [
+                0x11u8, 0x04, 0x12, 0x01, 0x34, 0x56, b't', b'e', b's', b't', b'-', b'c', b'l',
+                b'i', b'e', b'n', b't'
+            ]
+        );
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn connect_max_client_id_encode_parse() {
+        let mut bytes = [0u8; 80];
         let mut len = 0usize;
-        let expected = ForwardedMessage {
-            ctrl: 0,
-            wireless_node_id: WirelessNodeId::from("test-node"),
-            message: Message::PingResp(PingResp {}),
+        let client_id = [b'c'; 64];
+        let expected = Message::connect(
+            core::str::from_utf8(&client_id).unwrap(),
+            0x3456,
+            true,
+            false,
+        );
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq!(len, 70);
+        assert_eq!(bytes[0], 70);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+
+        bytes[0] = 71;
+        bytes[70] = b'c';
+        assert_eq!(
+            bytes.read::<Message>(&mut 0),
+            Err(byte::Error::BadInput {
+                err: "client_id longer than 64 bytes"
+            })
+        );
+    }
+
+    #[test]
+    fn connect_constructor() {
+        assert_eq!(
+            Message::connect("id", 30, true, false),
+            Message::Connect(Connect {
+                flags: Flags(0x04),
+                duration: 30,
+                client_id: ClientId::from("id"),
+            })
+        );
+        assert_eq!(
+            Message::connect("id", 30, false, true),
+            Message::Connect(Connect {
+                flags: Flags(0x08),
+                duration: 30,
+                client_id: ClientId::from("id"),
+            })
+        );
+    }
+
+    #[test]
+    fn connect_validate_duration() {
+        let connect = Connect {
+            flags: Flags::default(),
+            duration: 3600,
+            client_id: ClientId::from("id"),
+        };
+        assert_eq!(connect.validate_duration(3600), Ok(()));
+        assert_eq!(
+            connect.validate_duration(600),
+            Err(DecodeError::DurationTooLong {
+                duration: 3600,
+                max: 600
+            })
+        );
+    }
+
+    #[test]
+    fn connack_trailing_tlv() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::ConnAck(ConnAck {
+            code: ReturnCode::Accepted,
+            extra: ByteBuf::from_slice(&[0x80, 0x02, 0xab, 0xcd]).unwrap(),
+        });
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x07u8, 0x05, 0x00, 0x80, 0x02, 0xab, 0xcd]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+        let mut tlvs = actual.as_conn_ack().unwrap().trailing_tlvs();
+        assert_eq!(tlvs.next(), Some((0x80, &[0xab, 0xcd][..])));
+        assert_eq!(tlvs.next(), None);
+
+        // A truncated entry ends iteration.
+        assert_eq!(TlvIter::new(&[0x80, 0x03, 0xab]).next(), None);
+    }
+
+    #[test]
+    fn connack_extra_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::ConnAck(ConnAck {
+            code: ReturnCode::Rejected(RejectedReason::Congestion),
+            extra: ByteBuf::from_slice(&[0x1e]).unwrap(),
+        });
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x04u8, 0x05, 0x01, 0x1e]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual.as_conn_ack().unwrap().extra(), &[0x1e]);
+        assert_eq!(actual, expected);
+
+        let actual: Message = [0x03u8, 0x05, 0x00].read(&mut 0).unwrap();
+        assert_eq!(actual, Message::conn_ack(ReturnCode::Accepted));
+    }
+
+    #[test]
+    fn willtopic_empty_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::WillTopic(WillTopic::empty());
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x02u8, 0x07]);
+        assert_eq!(len, expected.encoded_len());
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert!(actual.as_will_topic().unwrap().is_empty());
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn willtopic_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let will_topic = WillTopic::new("test", QoS::ExactlyOnce, true);
+        assert_eq!(will_topic.qos(), QoS::ExactlyOnce);
+        assert!(will_topic.retain());
+        let expected = Message::WillTopic(will_topic);
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x07u8, 0x07, 0x50, b't', b'e', b's', b't']);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn register_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::Register(Register {
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            topic_name: TopicName::from("test"),
+        });
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(
+            &bytes[..len],
+            [0x0au8, 0x0a, 0x12, 0x34, 0x56, 0x78, b't', b'e', b's', b't']
+        );
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn register_extended_len() {
+        let mut bytes = [0u8; 300];
+        let mut len = 0usize;
+        let name = [b't'; 250];
+        let expected = Message::Register(Register {
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            topic_name: name[..].into(),
+        });
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq!(len, 258);
+        assert_eq!(expected.encoded_len(), len);
+        assert_eq_hex!(
+            &bytes[..8],
+            [0x01u8, 0x01, 0x02, 0x0a, 0x12, 0x34, 0x56, 0x78]
+        );
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn register_multibyte_topic() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let name = TopicName::from("café/x");
+        assert_eq!(name.wire_len(), 7);
+        assert_eq!(name.as_str().unwrap().chars().count(), 6);
+        let expected = Message::register(0x1234, 0x5678, "café/x");
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq_hex!(
+            &bytes[..len],
+            [0x0du8, 0x0a, 0x12, 0x34, 0x56, 0x78, b'c', b'a', b'f', 0xc3, 0xa9, b'/', b'x']
+        );
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn register_zero_msg_id() {
+        let bytes = [0x0au8, 0x0a, 0x12, 0x34, 0x00, 0x00, b't', b'e', b's', b't'];
+        let strict = ParseCtx::strict();
+        assert!(Message::parse(&bytes, ParseCtx::default()).is_ok());
+        assert_eq!(
+            Message::parse(&bytes, strict),
+            Err(DecodeError::ZeroMsgId.into())
+        );
+    }
+
+    #[test]
+    fn strict_checks_at_every_entry_point() {
+        let bytes = [0x0au8, 0x0a, 0x12, 0x34, 0x00, 0x00, b't', b'e', b's', b't'];
+        let strict = ParseCtx::strict();
+        assert_eq!(
+            Register::decode_ctx(&bytes, strict),
+            Err(DecodeError::ZeroMsgId)
+        );
+        assert_eq!(
+            parse_exact_with(&bytes, strict),
+            Err(DecodeError::ZeroMsgId)
+        );
+        assert_eq!(
+            MessageIter::new(&bytes, 1).with_ctx(strict).next(),
+            Some(Err(DecodeError::ZeroMsgId.into()))
+        );
+
+        let mut forwarded = [0u8; 15];
+        forwarded[..5].copy_from_slice(&[0x05, 0xfe, 0x00, b'n', b'1']);
+        forwarded[5..].copy_from_slice(&bytes);
+        assert!(matches!(
+            ForwardedMessageIter::new_forwarded(&forwarded, 1).next(),
+            Some(Ok(_))
+        ));
+        assert_eq!(
+            ForwardedMessageIter::new_forwarded(&forwarded, 1)
+                .with_ctx(strict)
+                .next(),
+            Some(Err(DecodeError::ZeroMsgId.into()))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_short_length_field() {
+        assert_eq!(
+            Message::parse(&[0x00, 0x17], ParseCtx::default()),
+            Err(DecodeError::BadLength {
+                declared: 0,
+                actual: 2
+            }
+            .into())
+        );
+        // The 3-byte form must also leave room for the type byte.
+        assert_eq!(
+            Message::parse(&[0x01, 0x00, 0x03, 0x17], ParseCtx::default()),
+            Err(DecodeError::BadLength {
+                declared: 3,
+                actual: 4
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn message_kind_name() {
+        assert_eq!(Message::ping_resp().kind_name(), "PINGRESP");
+        assert_eq!(Message::search_gw(1).kind_name(), "SEARCHGW");
+        assert_eq!(
+            Message::publish(Flags::default(), 1, 2, "x").kind_name(),
+            "PUBLISH"
+        );
+        assert_eq!(
+            Message::Unknown(Unknown {
+                msg_type: 0x03,
+                body: heapless::Vec::new()
+            })
+            .kind_name(),
+            "UNKNOWN"
+        );
+    }
+
+    #[test]
+    fn client_id_is_valid() {
+        assert!(ClientId::from("sensor-01").is_valid());
+        assert!(!ClientId::new().is_valid());
+        assert!(!ClientId::from("bad\nid").is_valid());
+        let long = ClientId::from("abcdefghijklmnopqrstuvwxyz0123");
+        assert!(!long.is_valid());
+        assert!(long.is_valid_with_max(64));
+
+        let mut bytes = [0u8; 40];
+        let mut len = 0usize;
+        bytes
+            .write(&mut len, Message::connect(&long, 30, true, false))
+            .unwrap();
+        let strict = ParseCtx::strict().with_max_client_id_len(ClientId::SPEC_MAX_LEN);
+        assert!(Message::parse(&bytes[..len], ParseCtx::default()).is_ok());
+        assert_eq!(
+            Message::parse(&bytes[..len], strict),
+            Err(DecodeError::InvalidClientId.into())
+        );
+        bytes = [0u8; 40];
+        len = 0;
+        bytes
+            .write(&mut len, Message::connect("sensor-01", 30, true, false))
+            .unwrap();
+        assert!(Message::parse(&bytes[..len], strict).is_ok());
+    }
+
+    #[test]
+    fn return_code_to_mqtt_reason() {
+        assert_eq!(ReturnCode::Accepted.to_mqtt_reason(), 0x00);
+        assert_eq!(
+            ReturnCode::from(RejectedReason::Congestion).to_mqtt_reason(),
+            0x97
+        );
+        assert_eq!(
+            ReturnCode::from(RejectedReason::InvalidTopicId).to_mqtt_reason(),
+            0x90
+        );
+        assert_eq!(
+            ReturnCode::from(RejectedReason::NotSupported).to_mqtt_reason(),
+            0x83
+        );
+        assert_eq!(
+            ReturnCode::from(RejectedReason::Reserved(0x2a)).to_mqtt_reason(),
+            0x80
+        );
+    }
+
+    #[test]
+    fn return_code_from_mqtt_reason() {
+        assert_eq!(ReturnCode::from_mqtt_reason(0x00), ReturnCode::Accepted);
+        assert_eq!(ReturnCode::from_mqtt_reason(0x01), ReturnCode::Accepted);
+        for reason in [0x89, 0x97, 0x9f] {
+            assert_eq!(
+                ReturnCode::from_mqtt_reason(reason),
+                RejectedReason::Congestion.into()
+            );
+        }
+        for reason in [0x8f, 0x90] {
+            assert_eq!(
+                ReturnCode::from_mqtt_reason(reason),
+                RejectedReason::InvalidTopicId.into()
+            );
+        }
+        for reason in [0x80, 0x83, 0x87] {
+            assert_eq!(
+                ReturnCode::from_mqtt_reason(reason),
+                RejectedReason::NotSupported.into()
+            );
+        }
+    }
+
+    #[test]
+    fn publish_max_encoded_len() {
+        assert_eq!(max_encoded_len(MessageType::Publish), 9 + 256);
+        let mut data = PublishData::new();
+        data.resize(256, b'x').unwrap();
+        let msg = Message::Publish(Publish {
+            flags: Flags::default(),
+            topic_id: 1,
+            msg_id: 2,
+            data,
+        });
+        assert_eq!(msg.encoded_len(), max_encoded_len(msg.msg_type()));
+    }
+
+    #[test]
+    fn max_encoded_len_bounds_every_variant() {
+        let full = [b'a'; 256];
+        let name = |len: usize| TopicName(heapless::Vec::from_slice(&full[..len]).unwrap());
+        let client_id = || ClientId::from(core::str::from_utf8(&full[..64]).unwrap());
+        let extra = || ByteBuf::from_slice(&full[..MAX_EXTENSION_LEN]).unwrap();
+        let code = ReturnCode::Accepted;
+        let msgs = [
+            Message::search_gw(0xff),
+            Message::GwInfo(GwInfo {
+                gw_id: 1,
+                gw_add: Some(GwAddress::try_from(&full[..GwAddress::MAX_LEN]).unwrap()),
+            }),
+            Message::Connect(Connect {
+                flags: Flags::default(),
+                duration: 60,
+                client_id: client_id(),
+            }),
+            Message::ConnAck(ConnAck {
+                code,
+                extra: extra(),
+            }),
+            Message::WillTopic(WillTopic {
+                flags: Flags::default(),
+                topic_name: name(0xff - 3),
+            }),
+            Message::Register(Register {
+                topic_id: 1,
+                msg_id: 2,
+                topic_name: name(256),
+            }),
+            Message::RegAck(RegAck {
+                topic_id: 1,
+                msg_id: 2,
+                code,
+                extra: extra(),
+            }),
+            Message::Publish(Publish {
+                flags: Flags::default(),
+                topic_id: 1,
+                msg_id: 2,
+                data: PublishData::from(&full[..]),
+            }),
+            Message::PubAck(PubAck {
+                topic_id: 1,
+                msg_id: 2,
+                code,
+            }),
+            Message::Subscribe(Subscribe {
+                flags: Flags::default(),
+                msg_id: 2,
+                topic: TopicNameOrId::Name(name(256)),
+            }),
+            Message::SubAck(SubAck {
+                flags: Flags::default(),
+                msg_id: 2,
+                topic_id: 1,
+                code,
+            }),
+            Message::Unsubscribe(Unsubscribe {
+                flags: Flags::default(),
+                msg_id: 2,
+                topic: TopicNameOrId::Name(name(256)),
+            }),
+            Message::UnsubAck(UnsubAck { msg_id: 2, code }),
+            Message::PingReq(PingReq {
+                client_id: Some(client_id()),
+            }),
+            Message::ping_resp(),
+            Message::Disconnect(Disconnect { duration: Some(60) }),
+            Message::Unknown(Unknown {
+                msg_type: 0x03,
+                body: heapless::Vec::from_slice(&full[..]).unwrap(),
+            }),
+        ];
+        let mut bytes = [0u8; SCRATCH_LEN];
+        for msg in &msgs {
+            let max = max_encoded_len(msg.msg_type());
+            assert!(msg.encoded_len() <= max, "{:?}", msg.msg_type());
+            let mut len = 0usize;
+            bytes.write(&mut len, msg).unwrap();
+            assert_eq!(len, msg.encoded_len(), "{:?}", msg.msg_type());
+        }
+    }
+
+    #[test]
+    fn topic_name_set() {
+        let mut name = TopicName::from("a/b");
+        assert_eq!(name.set("c/d"), Ok(()));
+        assert_eq!(&name, "c/d");
+        let long = [b'x'; 257];
+        assert_eq!(name.set(core::str::from_utf8(&long).unwrap()), Err(()));
+        assert_eq!(&name, "c/d");
+
+        let mut id = ClientId::from("old");
+        assert_eq!(id.set("new"), Ok(()));
+        assert_eq!(id.as_str(), "new");
+        assert_eq!(id.set(core::str::from_utf8(&long[..65]).unwrap()), Err(()));
+        assert_eq!(id.as_str(), "new");
+    }
+
+    #[test]
+    fn topic_name_full_via_deref_mut() {
+        let mut name = TopicName::new();
+        while name.push(b'x').is_ok() {}
+        assert_eq!(name.len(), 256);
+        let mut bytes = [0u8; 300];
+        let mut len = 0usize;
+        let expected = Message::subscribe(Flags::default(), 1, TopicNameOrId::Name(name));
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq!(len, 263);
+        assert_eq_hex!(&bytes[..3], [0x01u8, 0x01, 0x07]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn visitor_counts_publishes() {
+        #[derive(Default)]
+        struct Counter {
+            publishes: usize,
+            bytes: usize,
+        }
+        impl MessageVisitor for Counter {
+            fn visit_publish(&mut self, msg: &Publish) {
+                self.publishes += 1;
+                self.bytes += msg.data.len();
+            }
+        }
+
+        let msgs = [
+            Message::publish(Flags::default(), 1, 1, "abc"),
+            Message::ping_resp(),
+            Message::pub_ack(1, 1, ReturnCode::Accepted),
+            Message::publish(Flags::default(), 1, 2, "de"),
+        ];
+        let mut counter = Counter::default();
+        for msg in &msgs {
+            msg.accept(&mut counter);
+        }
+        assert_eq!(counter.publishes, 2);
+        assert_eq!(counter.bytes, 5);
+    }
+
+    #[test]
+    fn encode_buffer_too_small() {
+        let msg = Message::publish(Flags::default(), 1, 2, "abc");
+        let mut buf = [0u8; 3];
+        assert_eq!(
+            msg.encode(&mut buf),
+            Err(EncodeError::BufferTooSmall {
+                needed: 10,
+                available: 3
+            })
+        );
+        assert_eq!(buf, [0u8; 3]);
+        let mut buf = [0u8; 10];
+        assert_eq!(msg.encode(&mut buf), Ok(10));
+    }
+
+    #[test]
+    fn parse_rejects_len_below_type_minimum() {
+        let bytes = [0x05u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x00];
+        assert_eq!(
+            Message::parse(&bytes, ParseCtx::default()),
+            Err(DecodeError::BadLength {
+                declared: 5,
+                actual: 7
+            }
+            .into())
+        );
+        let bytes = [0x01u8, 0x00, 0x07, 0x0b, 0x12, 0x34, 0x56];
+        assert!(Message::parse(&bytes, ParseCtx::default()).is_err());
+        assert_eq!(min_len(0x17), 2);
+        assert_eq!(min_len(0x0b), 7);
+    }
+
+    #[test]
+    fn topic_name_levels() {
+        let filter = TopicName::from("a/+/c");
+        assert!(filter.levels().eq(["a", "+", "c"]));
+        assert!(TopicName::from("/a/").levels().eq(["", "a", ""]));
+        let invalid: TopicName = [b'a', 0xff][..].into();
+        assert_eq!(invalid.levels().count(), 0);
+
+        let name = TopicName::from("a/b/c");
+        assert_eq!(name.level(1), Some("b"));
+        assert_eq!(name.level(5), None);
+    }
+
+    #[test]
+    fn connect_bounded_client_id() {
+        assert_eq!(
+            ClientId::<23>::try_new("abcdefghijklmnopqrstuvwxyz0123"),
+            Err(())
+        );
+        let msg = Connect::<23> {
+            flags: Flags::default(),
+            duration: 30,
+            client_id: ClientId::try_new("sensor-01").unwrap(),
+        };
+        let mut buf = [0u8; 64];
+        let len = msg.encode(&mut buf).unwrap();
+        assert_eq!(Connect::<23>::decode(&buf[..len]), Ok((msg, len)));
+
+        // A 30-byte id parses as a plain CONNECT but not as a bounded one.
+        let len = Message::connect("abcdefghijklmnopqrstuvwxyz0123", 30, false, false)
+            .encode(&mut buf)
+            .unwrap();
+        assert_eq!(
+            Connect::<23>::decode(&buf[..len]),
+            Err(DecodeError::BadInput("client_id longer than capacity"))
+        );
+    }
+
+    #[test]
+    fn connect_keepalive_duration() {
+        use core::time::Duration;
+        let msg = Connect::fresh("client", 0, false).with_keepalive(Duration::from_secs(30));
+        assert_eq!(msg.duration, 30);
+        assert_eq!(msg.keepalive_duration(), Duration::from_secs(30));
+        let msg = msg.with_keepalive(Duration::from_secs(19 * 3600));
+        assert_eq!(msg.duration, u16::MAX);
+    }
+
+    #[test]
+    fn connect_session_modes() {
+        let resume = Connect::resume("client", 60);
+        assert_eq!(resume.flags.bits(), 0);
+        assert!(!resume.flags.clean_session());
+        assert!(!resume.flags.will());
+
+        let fresh = Connect::fresh("client", 60, false);
+        assert_eq!(fresh.flags.bits(), Flags::CLEAN_SESSION);
+        let fresh = Connect::fresh("client", 60, true);
+        assert_eq!(fresh.flags.bits(), Flags::CLEAN_SESSION | Flags::WILL);
+        assert_eq!(fresh.client_id.as_str(), "client");
+        assert_eq!(fresh.duration, 60);
+    }
+
+    #[test]
+    fn decode_needed_partial() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let msg = Message::publish(Flags::default(), 1, 2, "hello");
+        bytes.write(&mut len, &msg).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(decode_needed(&bytes[..3]), Err(Needed::NeedMoreBytes(9)));
+        assert_eq!(decode_needed(&[]), Err(Needed::NeedMoreBytes(1)));
+        assert_eq!(decode_needed(&[0x01, 0x01]), Err(Needed::NeedMoreBytes(1)));
+        assert_eq!(decode_needed(&bytes), Ok((msg, 12)));
+        assert!(matches!(
+            decode_needed(&[0x00, 0x17]),
+            Err(Needed::Error(_))
+        ));
+    }
+
+    #[test]
+    fn publish_new_qos_msg_id() {
+        let msg = Publish::new_qos(QoS::AtMostOnce, 1, Some(5), b"x").unwrap();
+        assert_eq!(msg.msg_id, 0);
+        assert_eq!(QoS::from(msg.flags.qos()), QoS::AtMostOnce);
+        let msg = Publish::new_qos(QoS::MinusOne, 1, None, b"x").unwrap();
+        assert_eq!(msg.msg_id, 0);
+        for qos in [QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            assert_eq!(
+                Publish::new_qos(qos, 1, None, b"x"),
+                Err(EncodeError::BadInput("msg_id must be nonzero"))
+            );
+            assert_eq!(
+                Publish::new_qos(qos, 1, Some(0), b"x"),
+                Err(EncodeError::BadInput("msg_id must be nonzero"))
+            );
+            let msg = Publish::new_qos(qos, 1, Some(7), b"x").unwrap();
+            assert_eq!(msg.msg_id, 7);
+            assert_eq!(QoS::from(msg.flags.qos()), qos);
+        }
+        assert_eq!(
+            Publish::new_qos(QoS::AtMostOnce, 1, None, &[0; 257]),
+            Err(EncodeError::BadInput("payload longer than 256 bytes"))
+        );
+    }
+
+    #[test]
+    fn publish_with_dup_only_sets_dup() {
+        let msg = Publish::new_qos(QoS::AtLeastOnce, 1, Some(7), b"x")
+            .unwrap()
+            .with_retain(true);
+        let resent = msg.clone().with_dup(true);
+        assert_eq!(resent.flags.bits() ^ msg.flags.bits(), Flags::DUP);
+        assert_eq!(resent.with_flags(msg.flags), msg);
+        let msg = msg.with_qos(QoS::ExactlyOnce);
+        assert_eq!(QoS::from(msg.flags.qos()), QoS::ExactlyOnce);
+        assert!(msg.flags.retain());
+        assert_eq!(msg.msg_id, 7);
+    }
+
+    #[test]
+    fn publish_clear_retained() {
+        let msg = Publish::clear_retained(0x1234, TopicIdType::Predefined);
+        assert!(msg.flags.retain());
+        assert_eq!(msg.flags.qos(), 0);
+        assert_eq!(
+            TopicIdType::from(msg.flags.topic_id_type()),
+            TopicIdType::Predefined
+        );
+        assert_eq!(msg.topic_id, 0x1234);
+        assert!(msg.data.is_empty());
+    }
+
+    #[test]
+    fn encoder_reuse() {
+        let mut encoder = Encoder::<32>::new();
+        assert_eq_hex!(
+            encoder
+                .encode(&Message::publish(Flags::default(), 1, 2, "abc"))
+                .unwrap(),
+            [0x0au8, 0x0c, 0x00, 0x00, 0x01, 0x00, 0x02, b'a', b'b', b'c']
+        );
+        assert_eq_hex!(
+            encoder.encode(&Message::ping_resp()).unwrap(),
+            [0x02u8, 0x17]
+        );
+        assert_eq_hex!(encoder.as_slice(), [0x02u8, 0x17]);
+
+        let mut small = Encoder::<4>::new();
+        assert!(small
+            .encode(&Message::publish(Flags::default(), 1, 2, "abc"))
+            .is_err());
+        assert!(small.as_slice().is_empty());
+    }
+
+    #[test]
+    fn encode_decode_free_fns() {
+        let msgs = [
+            Message::publish(Flags::default(), 1, 2, "abc"),
+            Message::ping_resp(),
+        ];
+        let mut buf = [0u8; 32];
+        let mut len = 0;
+        for msg in &msgs {
+            len += encode(msg, &mut buf[len..]).unwrap();
+        }
+        let (first, used) = decode(&buf[..len]).unwrap();
+        assert_eq!(first, msgs[0]);
+        assert_eq!(decode(&buf[used..len]), Ok((msgs[1].clone(), 2)));
+        assert_eq!(decode(&buf[..3]), Err(DecodeError::Incomplete));
+    }
+
+    #[test]
+    fn parse_exact_trailing_bytes() {
+        assert_eq!(parse_exact(&[0x02, 0x17]), Ok(Message::ping_resp()));
+        assert_eq!(
+            parse_exact(&[0x02, 0x17, 0x00]),
+            Err(DecodeError::BadLength {
+                declared: 2,
+                actual: 3
+            })
+        );
+        assert_eq!(parse_exact(&[0x02]), Err(DecodeError::Incomplete));
+    }
+
+    #[test]
+    fn ack_reply_to() {
+        let register = Register {
+            topic_id: 0,
+            msg_id: 0x1234,
+            topic_name: TopicName::from("a/b"),
+        };
+        let ack = RegAck::reply_to(&register, 7, ReturnCode::Accepted);
+        assert_eq!(ack.check(), Ok((7, 0x1234)));
+
+        let publish = Publish {
+            flags: Flags::default(),
+            topic_id: 7,
+            msg_id: 0x5678,
+            data: PublishData::from("x"),
+        };
+        let ack = PubAck::reply_to(&publish, RejectedReason::InvalidTopicId.into());
+        assert_eq!(ack.topic_id, 7);
+        assert_eq!(ack.msg_id, 0x5678);
+        assert_eq!(ack.code, RejectedReason::InvalidTopicId.into());
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn parse_logs_header() {
+        use std::string::String;
+        use std::sync::Mutex;
+        use std::vec::Vec;
+
+        static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        struct Capture;
+        impl log::Log for Capture {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                RECORDS.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+        static LOGGER: Capture = Capture;
+        log::set_logger(&LOGGER).ok();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let _: Message = [0x02u8, 0x17].read(&mut 0).unwrap();
+        assert!(RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r == "header: type 0x17, len 2"));
+        assert!(Message::parse(&[0x05, 0x0b, 0, 0, 0], ParseCtx::default()).is_err());
+        assert!(RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r.starts_with("bad header: ")));
+    }
+
+    #[test]
+    fn roundtrip_all_variants() {
+        let msgs = [
+            Message::search_gw(1),
+            Message::gw_info(1),
+            Message::connect("client", 30, true, true),
+            Message::conn_ack(ReturnCode::Accepted),
+            Message::will_topic("will", QoS::AtLeastOnce, true),
+            Message::WillTopic(WillTopic::empty()),
+            Message::register(1, 2, "a/b"),
+            Message::reg_ack(1, 2, ReturnCode::Accepted),
+            Message::publish(Flags::default(), 1, 2, "data"),
+            Message::pub_ack(1, 2, ReturnCode::Accepted),
+            Message::subscribe(Flags::default(), 1, TopicNameOrId::Name("a/#".into())),
+            Message::sub_ack(Flags::default(), 1, 2, ReturnCode::Accepted),
+            Message::unsubscribe(Flags::default(), 1, TopicNameOrId::Name("a/+".into())),
+            Message::unsub_ack(1, ReturnCode::Accepted),
+            Message::ping_req(Some("client")),
+            Message::ping_req(None),
+            Message::ping_resp(),
+            Message::disconnect(None),
+            Message::disconnect(Some(600)),
+            Message::Unknown(Unknown {
+                msg_type: 0x03,
+                body: heapless::Vec::from_slice(&[1, 2, 3]).unwrap(),
+            }),
+        ];
+        for msg in &msgs {
+            assert!(roundtrip(msg), "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn disconnect_encode_parse() {
+        let mut bytes = [0u8; 8];
+        let len = Message::disconnect(None).encode(&mut bytes).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x02u8, 0x18]);
+        let len = Message::disconnect(Some(600)).encode(&mut bytes).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x04u8, 0x18, 0x02, 0x58]);
+        assert_eq!(
+            parse_exact(&bytes[..len]),
+            Ok(Message::disconnect(Some(600)))
+        );
+        assert!(parse_exact(&[0x03, 0x18, 0x02]).is_err());
+    }
+
+    #[test]
+    fn from_parts_round_trip() {
+        let msgs = [
+            Message::Publish(Publish::from_parts(0x21, 1, 2, b"payload").unwrap()),
+            Message::Connect(Connect::from_parts(0x04, 30, "client").unwrap()),
+            Message::WillTopic(WillTopic::from_parts(0x30, b"will").unwrap()),
+            Message::Register(Register::from_parts(1, 2, b"a/b").unwrap()),
+            Message::Unknown(Unknown::from_parts(0x03, &[1, 2, 3]).unwrap()),
+            Message::GwInfo(GwInfo::from_parts(1, &[10, 0, 0, 1]).unwrap()),
+            Message::GwInfo(GwInfo::from_parts(1, &[]).unwrap()),
+            Message::ConnAck(ConnAck::from_parts(1, &[0x17, 0x00]).unwrap()),
+            Message::RegAck(RegAck::from_parts(1, 2, 0, &[]).unwrap()),
+            Message::Subscribe(Subscribe::from_parts(0x20, 3, b"a/#").unwrap()),
+            Message::Subscribe(Subscribe::from_parts(0x21, 3, &[0x12, 0x34]).unwrap()),
+            Message::Unsubscribe(Unsubscribe::from_parts(0x02, 3, b"ab").unwrap()),
+            Message::PingReq(PingReq::from_parts("client").unwrap()),
+            Message::PingReq(PingReq::from_parts("").unwrap()),
+        ];
+        for msg in &msgs {
+            assert!(roundtrip(msg), "{:?}", msg);
+        }
+        assert_eq!(
+            msgs[0],
+            Message::publish(Flags::from_bits(0x21), 1, 2, "payload")
+        );
+        assert_eq!(Publish::from_parts(0, 1, 2, &[0; 257]), Err(()));
+        assert_eq!(Register::from_parts(1, 2, &[b'a'; 257]), Err(()));
+        assert_eq!(Unknown::from_parts(0x0c, &[]), Err(()));
+        assert_eq!(GwInfo::from_parts(1, &[0; 33]), Err(()));
+        assert_eq!(ConnAck::from_parts(0, &[0; 17]), Err(()));
+        assert_eq!(Subscribe::from_parts(0x01, 3, &[0x12]), Err(()));
+        assert_eq!(
+            PingReq::from_parts(core::str::from_utf8(&[b'a'; 65]).unwrap()),
+            Err(())
+        );
+        assert_eq!(
+            Connect::from_parts(0, 30, core::str::from_utf8(&[b'a'; 65]).unwrap()),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn diff_reports_first_field() {
+        let a = Message::publish(Flags::default(), 1, 2, "data");
+        assert_eq!(diff(&a, &a.clone()), None);
+        assert_eq!(
+            diff(&a, &Message::publish(Flags::default(), 1, 3, "data")).as_deref(),
+            Some("msg_id: 2 != 3")
+        );
+        assert_eq!(
+            diff(&a, &Message::publish(Flags::default(), 1, 2, "dota")).as_deref(),
+            Some("data[1]: 0x61 != 0x6f")
+        );
+        assert_eq!(
+            diff(&a, &Message::publish(Flags::default(), 1, 2, "dat")).as_deref(),
+            Some("data: len 4 != 3")
+        );
+        assert_eq!(
+            diff(&a, &Message::ping_resp()).as_deref(),
+            Some("type: \"PUBLISH\" != \"PINGRESP\"")
+        );
+    }
+
+    #[test]
+    fn reserved_topic_id_type() {
+        let strict = ParseCtx::strict();
+        let mut flags = Flags::default();
+        flags.set_topic_id_type(TopicIdType::Reserved.into());
+        for msg in [
+            Message::publish(flags, 1, 2, "x"),
+            Message::subscribe(flags, 1, TopicNameOrId::Id(1)),
+        ] {
+            let mut bytes = [0u8; 16];
+            let mut len = 0usize;
+            bytes.write(&mut len, &msg).unwrap();
+            assert_eq!(
+                Message::parse(&bytes[..len], ParseCtx::default()),
+                Ok((msg, len))
+            );
+            assert_eq!(
+                Message::parse(&bytes[..len], strict),
+                Err(DecodeError::InvalidTopicIdType(3).into())
+            );
+        }
+    }
+
+    #[test]
+    fn message_default_array() {
+        let mut msgs: [Message; 4] = Default::default();
+        assert!(msgs.iter().all(|msg| *msg == Message::ping_resp()));
+        msgs[1] = Message::search_gw(0);
+        assert_eq!(msgs[1].kind_name(), "SEARCHGW");
+    }
+
+    #[test]
+    fn strict_ack_length_mismatch() {
+        let strict = ParseCtx::strict();
+        // REGACK claiming 8 bytes: the trailing byte is read as an extension
+        // unless parsing strictly, where it's a truncated TLV.
+        let over = [0x08u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x00, 0x17];
+        match Message::parse(&over, ParseCtx::default()) {
+            Ok((Message::RegAck(msg), 8)) => assert_eq!(&msg.extra[..], &[0x17]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            Message::parse(&over, strict),
+            Err(DecodeError::BadLength {
+                declared: 8,
+                actual: 7
+            }
+            .into())
+        );
+        // A complete TLV is a valid extension even when parsing strictly.
+        let tlv = [0x09u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x00, 0x17, 0x00];
+        match Message::parse(&tlv, strict) {
+            Ok((Message::RegAck(msg), 9)) => assert_eq!(&msg.extra[..], &[0x17, 0x00]),
+            other => panic!("unexpected {:?}", other),
+        }
+        // REGACK claiming 6 bytes can't hold its return code.
+        let under = [0x06u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x00];
+        for ctx in [ParseCtx::default(), strict] {
+            assert_eq!(
+                Message::parse(&under, ctx),
+                Err(DecodeError::BadLength {
+                    declared: 6,
+                    actual: 7
+                }
+                .into())
+            );
+        }
+        // Extension bytes past MAX_EXTENSION_LEN are left unread rather than
+        // failing the parse, so only strict parsing rejects them.
+        let mut long = [0u8; 24];
+        long[..7].copy_from_slice(&[0x18, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x00]);
+        match Message::parse(&long, ParseCtx::default()) {
+            Ok((Message::RegAck(msg), 23)) => assert_eq!(msg.extra.len(), MAX_EXTENSION_LEN),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            Message::parse(&long, strict),
+            Err(DecodeError::BadLength {
+                declared: 24,
+                actual: 23
+            }
+            .into())
+        );
+        // PUBACK claiming 8 bytes leaves one unread.
+        let puback = [0x08u8, 0x0d, 0x12, 0x34, 0x56, 0x78, 0x00, 0x17];
+        assert_eq!(
+            Message::parse(&puback, ParseCtx::default()).map(|(_msg, len)| len),
+            Ok(7)
+        );
+        assert_eq!(
+            Message::parse(&puback, strict),
+            Err(DecodeError::BadLength {
+                declared: 8,
+                actual: 7
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn enum_value_tables() {
+        let qos: std::vec::Vec<u8> = QoS::all().iter().map(|(q, _)| (*q).into()).collect();
+        assert_eq!(qos, [0, 1, 2, 3]);
+        for (qos, _name) in QoS::all() {
+            assert_eq!(QoS::from(u8::from(*qos)), *qos);
+        }
+        assert_eq!(TopicIdType::all().len(), 4);
+        for (reason, name) in RejectedReason::named() {
+            assert_eq!(reason.to_string(), *name);
+        }
+    }
+
+    #[test]
+    fn sub_ack_downgrade() {
+        let mut flags = Flags::default();
+        flags.set_qos(QoS::AtLeastOnce.into());
+        let ack = SubAck {
+            flags,
+            msg_id: 1,
+            topic_id: 2,
+            code: ReturnCode::Accepted,
         };
+        assert_eq!(ack.granted_qos(), QoS::AtLeastOnce);
+        assert!(ack.is_downgrade_from(QoS::ExactlyOnce));
+        assert!(!ack.is_downgrade_from(QoS::AtLeastOnce));
+        assert!(!ack.is_downgrade_from(QoS::AtMostOnce));
+        assert!(!ack.is_downgrade_from(QoS::MinusOne));
+    }
+
+    #[test]
+    fn decode_error_display() {
+        assert_eq!(
+            DecodeError::UnknownMessageType(0x03).to_string(),
+            "unknown message type 0x03"
+        );
+        assert_eq!(
+            DecodeError::BadLength {
+                declared: 10,
+                actual: 7
+            }
+            .to_string(),
+            "length field is 10 bytes but message is 7 bytes"
+        );
+    }
+
+    #[test]
+    fn flags_fields_disjoint() {
+        fn fields(flags: Flags) -> [u8; 6] {
+            [
+                flags.dup() as u8,
+                flags.qos(),
+                flags.retain() as u8,
+                flags.will() as u8,
+                flags.clean_session() as u8,
+                flags.topic_id_type(),
+            ]
+        }
+        let setters: [fn(&mut Flags); 6] = [
+            |f| f.set_dup(true),
+            |f| f.set_qos(0b11),
+            |f| f.set_retain(true),
+            |f| f.set_will(true),
+            |f| f.set_clean_session(true),
+            |f| f.set_topic_id_type(0b11),
+        ];
+        let max = [1, 0b11, 1, 1, 1, 0b11];
+        let mut all = Flags::default();
+        for (i, set) in setters.iter().enumerate() {
+            let mut flags = Flags::default();
+            set(&mut flags);
+            set(&mut all);
+            let mut expected = [0u8; 6];
+            expected[i] = max[i];
+            assert_eq!(fields(flags), expected, "field {}", i);
+        }
+        // Together the fields cover the whole byte.
+        assert_eq!(all.bits(), 0xff);
+        assert_eq!(fields(all), max);
+    }
+
+    #[test]
+    fn flags_semantic_eq() {
+        let a = Flags::from_bits(Flags::CLEAN_SESSION);
+        let b = Flags::from_bits(Flags::CLEAN_SESSION | Flags::DUP | Flags::RETAIN);
+        assert_ne!(a, b);
+        assert!(a.semantic_eq(&b, Flags::CONNECT_MASK));
+        assert!(!a.semantic_eq(&b, Flags::PUBLISH_MASK));
+        let c = Flags::from_bits(Flags::CLEAN_SESSION | Flags::WILL);
+        assert!(!a.semantic_eq(&c, Flags::CONNECT_MASK));
+    }
+
+    #[test]
+    fn flags_from_bits() {
+        let flags = Flags::from_bits(Flags::DUP | Flags::RETAIN);
+        assert!(flags.dup());
+        assert!(flags.retain());
+        assert!(!flags.will());
+        assert!(!flags.clean_session());
+        assert_eq!(flags.qos(), 0);
+        assert_eq!(flags.bits(), 0x90);
+        let flags = Flags::from_bits(Flags::QOS_MASK & 0x20 | 0x02);
+        assert_eq!(flags.qos(), 1);
+        assert_eq!(flags.bits() & Flags::TOPIC_ID_TYPE_MASK, 0x02);
+    }
+
+    #[test]
+    fn connect_strict_flags() {
+        let strict = ParseCtx::strict();
+        let bytes = [0x08u8, 0x04, 0x0c, 0x01, 0x00, 0x1e, b'i', b'd'];
+        assert!(Message::parse(&bytes, strict).is_ok());
+        let bytes = [0x08u8, 0x04, 0x1c, 0x01, 0x00, 0x1e, b'i', b'd'];
+        assert!(Message::parse(&bytes, ParseCtx::default()).is_ok());
+        assert_eq!(
+            Message::parse(&bytes, strict),
+            Err(DecodeError::InvalidFlags(0x1c).into())
+        );
+    }
+
+    #[test]
+    fn forwarded_message_iter_parse() {
+        let forwarded = MaybeForwardedMessage::ForwardedMessage(ForwardedMessage {
+            ctrl: 0x01,
+            wireless_node_id: WirelessNodeId::from("node"),
+            message: Message::PingResp(PingResp {}),
+        });
+        let plain = MaybeForwardedMessage::Message(Message::SearchGw(SearchGw { radius: 5 }));
+        let mut bytes = [0u8; 32];
+        let mut len = 0usize;
+        bytes.write(&mut len, forwarded.clone()).unwrap();
+        bytes.write(&mut len, plain.clone()).unwrap();
+
+        let mut iter = ForwardedMessageIter::new_forwarded(&bytes[..len], 4);
+        assert_eq!(iter.next(), Some(Ok(forwarded)));
+        assert_eq!(iter.next(), Some(Ok(plain)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn classify_decode_errors() {
+        assert_eq!(classify_error(&[]), DecodeErrorKind::TooShort);
+        assert_eq!(classify_error(&[0x01, 0x00]), DecodeErrorKind::TooShort);
+        assert_eq!(
+            classify_error(&[0x07, 0x0d, 0x12, 0x34]),
+            DecodeErrorKind::TooShort
+        );
+        assert_eq!(classify_error(&[0x00, 0x17]), DecodeErrorKind::BadLength);
+        assert_eq!(
+            classify_error(&[0x01, 0x00, 0x03, 0x17]),
+            DecodeErrorKind::BadLength
+        );
+        let short_regack = [0x05, 0x0b, 0, 0, 0];
+        assert!(matches!(
+            short_regack.read::<Message>(&mut 0),
+            Err(byte::Error::BadInput {
+                err: "Length field doesn't match message length"
+            })
+        ));
+        assert_eq!(classify_error(&short_regack), DecodeErrorKind::BadLength);
+        assert_eq!(
+            classify_error(&[0x02, 0x06]),
+            DecodeErrorKind::UnknownType(0x06)
+        );
+
+        let mut bytes = [b'x'; 80];
+        bytes[..6].copy_from_slice(&[80, 0x04, 0x04, 0x01, 0x00, 0x1e]);
+        assert!(bytes.read::<Message>(&mut 0).is_err());
+        assert_eq!(classify_error(&bytes), DecodeErrorKind::FieldOverflow);
+    }
+
+    #[test]
+    fn subscribe_long_filter() {
+        let strict = ParseCtx::strict();
+        let mut filter = TopicName::new();
+        while filter.len() < 198 {
+            filter.extend_from_slice(b"abc/").unwrap();
+        }
+        filter.extend_from_slice(b"#").unwrap();
+        let expected = Message::subscribe(Flags::default(), 1, TopicNameOrId::Name(filter));
+        let mut bytes = [0u8; 300];
+        let mut len = 0usize;
+        bytes.write(&mut len, &expected).unwrap();
+        assert_eq!(len, 5 + 201);
+        assert_eq!(Message::parse(&bytes, strict), Ok((expected, len)));
+
+        let mut bytes = [b'a'; 308];
+        bytes[..7].copy_from_slice(&[0x01, 0x01, 0x34, 0x12, 0x00, 0x00, 0x01]);
+        assert_eq!(
+            bytes.read::<Message>(&mut 0),
+            Err(byte::Error::BadInput {
+                err: "topic_name longer than 256 bytes"
+            })
+        );
+    }
+
+    #[test]
+    fn subscribe_invalid_filter() {
+        let strict = ParseCtx::strict();
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let msg = Message::subscribe(Flags::default(), 1, TopicNameOrId::Name("a/#/b".into()));
+        bytes.write(&mut len, msg).unwrap();
+        assert!(Message::parse(&bytes, ParseCtx::default()).is_ok());
+        assert_eq!(
+            Message::parse(&bytes, strict),
+            Err(DecodeError::InvalidTopicFilter.into())
+        );
+        assert!(TopicName::from("a/+/c").is_valid_filter());
+        assert!(!TopicName::from("a/b+").is_valid_filter());
+    }
+
+    #[test]
+    fn register_patch_msg_id() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        bytes
+            .write(&mut len, Message::register(0x1234, 0x5678, "test"))
+            .unwrap();
+        patch_msg_id(&mut bytes[..len], 0x9abc).unwrap();
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, Message::register(0x1234, 0x9abc, "test"));
+
+        assert_eq!(msg_id_offset(0x0c, true), Some(7));
+        assert_eq!(msg_id_offset(0x17, false), None);
+        assert!(patch_msg_id(&mut [0x02, 0x17], 1).is_err());
+    }
+
+    #[test]
+    fn publish_eq_ignoring_dup() {
+        let original = Message::publish(Flags(0x20), 1, 2, "test");
+        let mut retransmit = original.clone();
+        if let Message::Publish(msg) = &mut retransmit {
+            msg.flags.set_dup(true);
+        }
+        assert_ne!(original, retransmit);
+        assert!(original.eq_ignoring_dup(&retransmit));
+        assert!(!original.eq_ignoring_dup(&Message::publish(Flags(0x20), 1, 3, "test")));
+    }
+
+    #[test]
+    fn message_classifiers() {
+        let connect = Message::connect("id", 30, true, false);
+        assert!(connect.is_connection_setup());
+        assert!(!connect.is_keepalive());
+        assert!(!connect.is_publish_flow());
+        let publish = Message::publish(Flags::default(), 1, 0, "a");
+        assert!(publish.is_publish_flow());
+        assert!(!publish.is_connection_setup());
+        assert!(Message::ping_req(None).is_keepalive());
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_message_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+        let mut seed = [0u8; 1024];
+        for round in 0..64u32 {
+            for (i, b) in seed.iter_mut().enumerate() {
+                *b = (i as u32)
+                    .wrapping_mul(2654435761)
+                    .wrapping_add(round * 40503) as u8;
+            }
+            let msg = Message::arbitrary(&mut Unstructured::new(&seed)).unwrap();
+            assert_eq!(msg.validate(), Ok(()));
+            let mut bytes = [0u8; 300];
+            let mut len = 0usize;
+            bytes.write(&mut len, &msg).unwrap();
+            assert_eq!(len, msg.encoded_len());
+            let strict = ParseCtx::strict();
+            let (decoded, consumed) = Message::parse(&bytes[..len], strict).unwrap();
+            assert_eq!(consumed, len);
+            assert_eq!(diff(&msg, &decoded), None);
+            assert_eq!(decoded, msg);
+            let body_len = msg.write_body(&mut bytes).unwrap();
+            assert_eq!(body_len, msg.body_len());
+            assert_eq!(Message::parse_body(bytes[0], &bytes[1..body_len]), Ok(msg));
+        }
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn shrink_publish_payload() {
+        let msg = Message::Publish(Publish {
+            flags: Flags(0x20),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            data: PublishData::from(&[0xab; 200][..]),
+        });
+        let shrunk = shrink_message(msg, |msg| match msg {
+            Message::Publish(msg) => !msg.data.is_empty(),
+            _ => false,
+        });
+        assert_eq!(
+            shrunk,
+            Message::Publish(Publish {
+                flags: Flags::default(),
+                topic_id: 0,
+                msg_id: 0,
+                data: PublishData::from(&[0xab][..]),
+            })
+        );
+    }
+
+    #[test]
+    fn message_sort() {
+        let mut msgs: heapless::Vec<Message, 8> = heapless::Vec::new();
+        msgs.extend([
+            Message::ping_resp(),
+            Message::publish(Flags::default(), 2, 0, "b"),
+            Message::Unknown(Unknown {
+                msg_type: 0x03,
+                body: heapless::Vec::new(),
+            }),
+            Message::search_gw(5),
+            Message::publish(Flags::default(), 1, 0, "a"),
+            Message::search_gw(1),
+        ]);
+        msgs.sort_unstable();
+        assert_eq!(
+            &msgs[..],
+            &[
+                Message::search_gw(1),
+                Message::search_gw(5),
+                Message::Unknown(Unknown {
+                    msg_type: 0x03,
+                    body: heapless::Vec::new(),
+                }),
+                Message::publish(Flags::default(), 1, 0, "a"),
+                Message::publish(Flags::default(), 2, 0, "b"),
+                Message::ping_resp(),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_cmp_unknown_with_assigned_code() {
+        let known = Message::ping_resp();
+        let unknown = Message::Unknown(Unknown {
+            msg_type: 0x17,
+            body: heapless::Vec::new(),
+        });
+        assert_eq!(known.cmp(&unknown), Ordering::Less);
+        assert_eq!(unknown.cmp(&known), Ordering::Greater);
+    }
+
+    #[test]
+    fn message_stream_only_publishes() {
+        let first = Message::publish(Flags::default(), 1, 0, "a");
+        let second = Message::publish(Flags::default(), 2, 0, "b");
+        let bytes = encode_batch::<64>(&[
+            Message::ping_resp(),
+            first.clone(),
+            Message::search_gw(5),
+            second.clone(),
+            Message::ping_resp(),
+        ])
+        .unwrap();
+        let mut iter = MessageIter::new(&bytes, 10).only_publishes();
+        assert_eq!(iter.next(), Some(Ok(first)));
+        assert_eq!(iter.next(), Some(Ok(second)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(
+            MessageIter::new(&bytes, 10)
+                .of_type(MessageType::PingResp)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn msg_id_allocator_skips_zero() {
+        let mut ids = MsgIdAllocator::new();
+        assert_eq!(ids.allocate(), 1);
+        let mut ids = MsgIdAllocator { last: 0xfffe };
+        assert_eq!(ids.allocate(), 0xffff);
+        assert_eq!(ids.allocate(), 1);
+    }
+
+    #[test]
+    fn regack_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::RegAck(RegAck {
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            code: ReturnCode::Rejected(RejectedReason::Congestion),
+            extra: ByteBuf::new(),
+        });
+        bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x07u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x1]);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn publish_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::Publish(Publish {
+            flags: Flags(0x12),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            data: PublishData::from("test"),
+        });
         bytes.write(&mut len, expected.clone()).unwrap();
         assert_eq_hex!(
             &bytes[..len],
-            &[12u8, 0xfe, 0x00, b't', b'e', b's', b't', b'-', b'n', b'o', b'd', b'e', 2, 0x17]
+            [0x0bu8, 0x0c, 0x12, 0x12, 0x34, 0x56, 0x78, b't', b'e', b's', b't']
         );
-        let actual: ForwardedMessage = bytes.read(&mut 0).unwrap();
-        assert_eq_hex!(actual, expected);
+        let actual: Message = bytes.read(&mut 0).unwrap();
+        assert_eq!(actual, expected);
     }
 
     #[test]
-    fn return_code_encode() {
-        let mut buf = [0u8; 5];
-        let mut offset = 0usize;
-        buf.write(&mut offset, ReturnCode::Accepted).unwrap();
-        buf.write(
-            &mut offset,
-            ReturnCode::Rejected(RejectedReason::Congestion),
-        )
-        .unwrap();
-        buf.write(
-            &mut offset,
-            ReturnCode::Rejected(RejectedReason::InvalidTopicId),
-        )
-        .unwrap();
-        buf.write(
-            &mut offset,
-            ReturnCode::Rejected(RejectedReason::NotSupported),
-        )
-        .unwrap();
-        buf.write(
-            &mut offset,
-            ReturnCode::Rejected(RejectedReason::Reserved(0x12)),
-        )
-        .unwrap();
-        assert_eq_hex!(&buf, &[0x00u8, 0x01u8, 0x02u8, 0x03u8, 0x12u8]);
+    fn publish_qos0_zero_msg_id() {
+        let bytes = [
+            0x0bu8, 0x0c, 0x00, 0x12, 0x34, 0x00, 0x00, b't', b'e', b's', b't',
+        ];
+        let strict = ParseCtx::strict();
+        let (actual, _) = Message::parse(&bytes, strict).unwrap();
+        assert_eq!(
+            actual,
+            Message::Publish(Publish {
+                flags: Flags::default(),
+                topic_id: 0x1234,
+                msg_id: 0,
+                data: PublishData::from("test"),
+            })
+        );
+    }
+
+    #[test]
+    fn publish_encode_by_ref() {
+        let msg = Publish {
+            flags: Flags(0x12),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            data: PublishData::from("test"),
+        };
+        let mut by_ref = [0u8; 20];
+        let mut ref_len = 0usize;
+        by_ref.write(&mut ref_len, &msg).unwrap();
+        let mut by_value = [0u8; 20];
+        let mut value_len = 0usize;
+        by_value.write(&mut value_len, msg).unwrap();
+        assert_eq_hex!(&by_ref[..ref_len], &by_value[..value_len]);
+    }
+
+    #[test]
+    fn publish_data_as_bytes() {
+        fn forward(payload: &[u8]) -> usize {
+            payload.len()
+        }
+        fn forward_ref<T: AsRef<[u8]>>(payload: T) -> usize {
+            payload.as_ref().len()
+        }
+        let msg = Publish {
+            flags: Flags::default(),
+            topic_id: 1,
+            msg_id: 2,
+            data: PublishData::from(&[0xde, 0xad, 0xbe, 0xef][..]),
+        };
+        assert_eq!(forward(&msg.data), 4);
+        assert_eq!(forward_ref(&msg.data), 4);
     }
 
     #[test]
-    fn return_code_parse() {
-        let buf = &[0x00u8, 0x01u8, 0x02u8, 0x03u8, 0x12u8];
-        let mut actual = [ReturnCode::Accepted; 5];
-        let mut offset = 0usize;
-        for i in 0..5 {
-            actual[i] = buf.read(&mut offset).unwrap();
-        }
+    fn publish_data_capacity() {
+        let mut data = PublishData::new();
+        assert_eq!(data.remaining(), PublishData::capacity());
+        data.try_extend_from_slice(&[0xaa; 200]).unwrap();
+        assert_eq!(data.remaining(), 56);
+        data.try_extend_from_slice(&[0xbb; 56]).unwrap();
+        assert_eq!(data.remaining(), 0);
+        assert_eq!(data.try_extend_from_slice(&[0xcc]), Err(()));
+        assert_eq!(data.len(), 256);
+        assert_eq!(data[255], 0xbb);
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn publish_data_base64_round_trip() {
+        let data = PublishData::from(&[0x00u8, 0xff, 0x10, 0x80, 0x7f][..]);
+        let encoded = data.to_base64::<16>().unwrap();
+        assert_eq!(encoded.as_str(), "AP8QgH8=");
+        assert_eq!(PublishData::from_base64(&encoded), Ok(data.clone()));
         assert_eq!(
-            &actual,
-            &[
-                ReturnCode::Accepted,
-                ReturnCode::Rejected(RejectedReason::Congestion),
-                ReturnCode::Rejected(RejectedReason::InvalidTopicId),
-                ReturnCode::Rejected(RejectedReason::NotSupported),
-                ReturnCode::Rejected(RejectedReason::Reserved(0x12)),
-            ]
+            data.to_base64::<4>(),
+            Err(EncodeError::BufferTooSmall {
+                needed: 8,
+                available: 4
+            })
+        );
+        assert_eq!(
+            PublishData::from_base64("not base64!"),
+            Err(DecodeError::BadInput("invalid base64"))
         );
     }
 
+    #[cfg(feature = "base64")]
     #[test]
-    fn searchgw_encode_parse() {
-        let bytes = &mut [0u8; 10];
-        let mut len = 0usize;
-        let expected = Message::SearchGw(SearchGw { radius: 5 });
-        bytes.write(&mut len, expected.clone()).unwrap();
-        assert_eq_hex!(&bytes[..len], [0x03u8, 0x01, 0x05]);
-        let actual: Message = bytes.read(&mut 0).unwrap();
-        assert_eq!(actual, expected);
+    fn publish_data_base64_overlong() {
+        let long = "AAAA".repeat(100);
+        assert_eq!(
+            PublishData::from_base64(&long),
+            Err(DecodeError::BadInput("payload longer than 256 bytes"))
+        );
+        // Fits the decode buffer but not the payload.
+        let just_over = "AAAA".repeat(86);
+        assert_eq!(
+            PublishData::from_base64(&just_over),
+            Err(DecodeError::BadInput("payload longer than 256 bytes"))
+        );
     }
 
     #[test]
-    fn gwinfo_encode_parse() {
-        let mut bytes = [0u8; 20];
-        let mut len = 0usize;
-        let expected = Message::GwInfo(GwInfo { gw_id: 0x12 });
-        bytes.write(&mut len, expected.clone()).unwrap();
-        assert_eq_hex!(&bytes[..len], [0x03u8, 0x02, 0x12]);
-        let actual: Message = bytes.read(&mut 0).unwrap();
-        assert_eq!(actual, expected);
+    fn publish_length_field_boundary() {
+        let mut bytes = [0u8; 300];
+        for (payload_len, header) in [
+            (248, &[0xffu8][..]),
+            (249, &[0x01, 0x01, 0x02][..]),
+            (256, &[0x01, 0x01, 0x09][..]),
+        ] {
+            let mut data = PublishData::new();
+            data.resize(payload_len, b'x').unwrap();
+            let expected = Message::Publish(Publish {
+                flags: Flags::default(),
+                topic_id: 1,
+                msg_id: 2,
+                data,
+            });
+            let mut len = 0usize;
+            bytes.write(&mut len, &expected).unwrap();
+            assert_eq!(len, header.len() + 6 + payload_len);
+            assert_eq_hex!(&bytes[..header.len()], header);
+            assert_eq!(bytes[..len].read::<Message>(&mut 0), Ok(expected));
+        }
     }
 
     #[test]
-    fn connect_encode_parse() {
-        let mut bytes = [0u8; 20];
+    fn publish_extended_len_encode_parse() {
+        let mut bytes = [0u8; 300];
         let mut len = 0usize;
-        let expected = Message::Connect(Connect {
+        let mut data = PublishData::new();
+        for _ in 0..250 {
+            data.push(b'x').unwrap();
+        }
+        let expected = Message::Publish(Publish {
             flags: Flags(0x12),
-            duration: 0x3456,
-            client_id: ClientId::from("test-client"),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            data,
         });
         bytes.write(&mut len, expected.clone()).unwrap();
+        assert_eq!(len, 259);
         assert_eq_hex!(
-            &bytes[..len],
-            [
-                0x11u8, 0x04, 0x12, 0x01, 0x34, 0x56, b't', b'e', b's', b't', b'-', b'c', b'l',
-                b'i', b'e', b'n', b't'
-            ]
+            &bytes[..8],
+            [0x01u8, 0x01, 0x03, 0x0c, 0x12, 0x12, 0x34, 0x56]
         );
         let actual: Message = bytes.read(&mut 0).unwrap();
         assert_eq!(actual, expected);
     }
 
+    #[cfg(feature = "embedded-io")]
+    struct MockWriter(heapless::Vec<u8, 512>);
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ErrorType for MockWriter {
+        type Error = core::convert::Infallible;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Write for MockWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.extend_from_slice(buf).unwrap();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
     #[test]
-    fn register_encode_parse() {
+    fn publish_streaming_write() {
+        let header = PublishHeader {
+            flags: Flags(0x12),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+        };
+        let mut w = MockWriter(heapless::Vec::new());
+        let len = write_publish_streaming(&header, b"test", &mut w).unwrap();
+        assert_eq!(len, 11);
+        assert_eq_hex!(
+            &w.0[..],
+            [0x0bu8, 0x0c, 0x12, 0x12, 0x34, 0x56, 0x78, b't', b'e', b's', b't']
+        );
+
+        let mut w = MockWriter(heapless::Vec::new());
+        let payload = [0xabu8; 300];
+        let len = write_publish_streaming(&header, &payload, &mut w).unwrap();
+        assert_eq!(len, 309);
+        assert_eq_hex!(
+            &w.0[..9],
+            [0x01u8, 0x01, 0x35, 0x0c, 0x12, 0x12, 0x34, 0x56, 0x78]
+        );
+        assert_eq!(&w.0[9..], &payload[..]);
+    }
+
+    /// Accepts `limit` bytes, then fails every write.
+    #[cfg(feature = "embedded-io")]
+    struct FailingWriter {
+        written: usize,
+        limit: usize,
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ErrorType for FailingWriter {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let n = buf.len().min(self.limit - self.written);
+            if n == 0 {
+                return Err(embedded_io::ErrorKind::BrokenPipe);
+            }
+            self.written += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn publish_streaming_write_error() {
+        let header = PublishHeader {
+            flags: Flags(0x12),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+        };
+        let mut w = FailingWriter {
+            written: 0,
+            limit: 10,
+        };
+        let err = write_publish_streaming(&header, b"test", &mut w).unwrap_err();
+        assert_eq!(err, WriteError::Io(embedded_io::ErrorKind::BrokenPipe));
+        assert_eq!(w.written, 10);
+        let mut msg = String::<32>::new();
+        core::fmt::write(&mut msg, format_args!("{}", err)).unwrap();
+        assert_eq!(msg, "io error: BrokenPipe");
+    }
+
+    #[test]
+    fn pingresp_body_encode_parse() {
+        let mut bytes = [0u8; 8];
+        let len = Message::PingResp(PingResp {})
+            .write_body(&mut bytes)
+            .unwrap();
+        assert_eq_hex!(&bytes[..len], [0x17u8]);
+        assert_eq!(
+            Message::parse_body(bytes[0], &bytes[1..len]),
+            Ok(Message::PingResp(PingResp {}))
+        );
+    }
+
+    #[test]
+    fn publish_body_encode_parse() {
+        let msg = Message::Publish(Publish {
+            flags: Flags(0x20),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            data: PublishData::from(&[0xab; 256][..]),
+        });
+        let mut bytes = [0u8; 300];
+        let len = msg.write_body(&mut bytes).unwrap();
+        assert_eq!(len, 262);
+        assert_eq_hex!(&bytes[..6], [0x0cu8, 0x20, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(Message::parse_body(bytes[0], &bytes[1..len]), Ok(msg));
+    }
+
+    #[test]
+    fn register_non_utf8_encode_parse() {
         let mut bytes = [0u8; 20];
         let mut len = 0usize;
         let expected = Message::Register(Register {
             topic_id: 0x1234,
             msg_id: 0x5678,
-            topic_name: TopicName::from("test"),
+            topic_name: (&[b'a', 0xff, b'b'][..]).into(),
         });
         bytes.write(&mut len, expected.clone()).unwrap();
         assert_eq_hex!(
             &bytes[..len],
-            [0x0au8, 0x0a, 0x12, 0x34, 0x56, 0x78, b't', b'e', b's', b't']
+            [0x09u8, 0x0a, 0x12, 0x34, 0x56, 0x78, b'a', 0xff, b'b']
         );
         let actual: Message = bytes.read(&mut 0).unwrap();
         assert_eq!(actual, expected);
+        if let Message::Register(msg) = actual {
+            assert!(msg.topic_name.as_str().is_err());
+        }
     }
 
     #[test]
-    fn regack_encode_parse() {
+    fn topic_name_matches_filter() {
+        let topic = TopicName::from("a/b/c");
+        assert!(topic.matches_filter(&TopicName::from("a/+/c")));
+        assert!(topic.matches_filter(&TopicName::from("a/#")));
+        assert!(topic.matches_filter(&TopicName::from("a/b/c")));
+        assert!(!topic.matches_filter(&TopicName::from("a/+")));
+        assert!(!topic.matches_filter(&TopicName::from("a/b/d")));
+        assert!(TopicName::from("a").matches_filter(&TopicName::from("a/#")));
+        assert!(!TopicName::from("$SYS/x").matches_filter(&TopicName::from("#")));
+        assert!(topic == *"a/b/c");
+    }
+
+    #[test]
+    fn publish_qos_minus_one() {
+        let msg = Publish::qos_minus_one(TopicRef::Predefined(0x0102), &[0x00, 0xff]).unwrap();
+        assert_eq!(QoS::from(msg.flags.qos()), QoS::MinusOne);
+        assert_eq!(
+            TopicIdType::from(msg.flags.topic_id_type()),
+            TopicIdType::Predefined
+        );
         let mut bytes = [0u8; 20];
         let mut len = 0usize;
-        let expected = Message::RegAck(RegAck {
+        bytes.write(&mut len, &msg).unwrap();
+        assert_eq_hex!(
+            &bytes[..len],
+            [0x09u8, 0x0c, 0x61, 0x01, 0x02, 0x00, 0x00, 0x00, 0xff]
+        );
+        let short = Publish::qos_minus_one(TopicRef::ShortName(*b"ab"), b"x").unwrap();
+        assert_eq!(short.topic_id, 0x6162);
+        assert_eq!(short.flags.topic_id_type(), 0b10);
+        assert_eq!(Publish::qos_minus_one(TopicRef::Normal(1), b"x"), Err(()));
+    }
+
+    #[test]
+    fn publish_validate_topic() {
+        let mut msg = Publish {
+            flags: Flags::default(),
+            topic_id: 0,
+            msg_id: 0,
+            data: PublishData::new(),
+        };
+        assert_eq!(msg.validate_topic(), Err(DecodeError::InvalidTopicId));
+        msg.flags.set_topic_id_type(TopicIdType::Predefined.into());
+        assert_eq!(msg.validate_topic(), Ok(()));
+        msg.flags.set_topic_id_type(TopicIdType::ShortName.into());
+        assert_eq!(msg.validate_topic(), Ok(()));
+        msg.flags.set_topic_id_type(TopicIdType::Reserved.into());
+        assert_eq!(
+            msg.validate_topic(),
+            Err(DecodeError::InvalidTopicIdType(3))
+        );
+        msg.flags.set_topic_id_type(TopicIdType::Normal.into());
+        msg.topic_id = 1;
+        assert_eq!(msg.validate_topic(), Ok(()));
+    }
+
+    #[test]
+    fn publish_fragment() {
+        let payload: heapless::Vec<u8, 300> = (0..300).map(|i| i as u8).collect();
+        let mut reassembled: heapless::Vec<u8, 300> = heapless::Vec::new();
+        let mut count = 0;
+        for (i, msg) in fragment_publish(0x1234, Flags::default(), 10, &payload, 64).enumerate() {
+            let msg = Message::Publish(msg);
+            assert!(msg.encoded_len() <= 64);
+            let msg = msg.as_publish().unwrap();
+            assert_eq!(msg.msg_id, 10 + i as u16);
+            reassembled.extend_from_slice(&msg.data).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 6);
+        assert_eq!(reassembled, payload);
+        assert_eq!(
+            fragment_publish(1, Flags::default(), 1, &payload, 7).count(),
+            0
+        );
+
+        assert_eq!(max_payload_for_mtu(64), 57);
+        assert_eq!(max_payload_for_mtu(257), 248);
+        assert_eq!(max_payload_for_mtu(258), 249);
+        assert_eq!(max_payload_for_mtu(1500), 256);
+    }
+
+    #[test]
+    fn publish_dedup_cache() {
+        let mut cache = DedupCache::<2>::new();
+        let mut msg = Publish {
+            flags: Flags(0x20),
             topic_id: 0x1234,
-            msg_id: 0x5678,
-            code: ReturnCode::Rejected(RejectedReason::Congestion),
+            msg_id: 1,
+            data: PublishData::from("test"),
+        };
+        assert!(!msg.is_duplicate());
+        assert!(!cache.seen(&msg));
+        msg.flags.set_dup(true);
+        assert!(msg.is_duplicate());
+        assert!(cache.seen(&msg));
+
+        for msg_id in 2..4 {
+            assert!(!cache.seen(&Publish {
+                msg_id,
+                ..msg.clone()
+            }));
+        }
+        assert!(!cache.seen(&msg));
+    }
+
+    #[test]
+    fn message_as_variant() {
+        let msg = Message::publish(Flags::default(), 0x1234, 0, "test");
+        assert_eq!(msg.as_publish().map(|p| p.topic_id), Some(0x1234));
+        assert_eq!(msg.as_connect(), None);
+        assert_eq!(Message::ping_resp().as_publish(), None);
+        assert_eq!(Message::ping_resp().as_ping_resp(), Some(&PingResp {}));
+    }
+
+    #[test]
+    fn reserved_type_encode_parse() {
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let expected = Message::Unknown(Unknown {
+            msg_type: 0x03,
+            body: heapless::Vec::from_slice(&[0xaa, 0xbb]).unwrap(),
         });
         bytes.write(&mut len, expected.clone()).unwrap();
-        assert_eq_hex!(&bytes[..len], [0x07u8, 0x0b, 0x12, 0x34, 0x56, 0x78, 0x1]);
+        assert_eq_hex!(&bytes[..len], [0x04u8, 0x03, 0xaa, 0xbb]);
         let actual: Message = bytes.read(&mut 0).unwrap();
         assert_eq!(actual, expected);
+
+        let truncated = [0x05u8, 0x03, 0xaa];
+        assert_eq!(
+            truncated.read::<Message>(&mut 0),
+            Err(byte::Error::Incomplete)
+        );
+        let malformed = [0x01u8, 0x03];
+        assert!(malformed.read::<Message>(&mut 0).is_err());
     }
 
     #[test]
-    fn publish_encode_parse() {
-        let mut bytes = [0u8; 20];
-        let mut len = 0usize;
-        let expected = Message::Publish(Publish {
-            flags: Flags(0x12),
+    fn puback_check() {
+        let mut ack = PubAck {
             topic_id: 0x1234,
             msg_id: 0x5678,
-            data: PublishData::from("test"),
-        });
-        bytes.write(&mut len, expected.clone()).unwrap();
-        assert_eq_hex!(
-            &bytes[..len],
-            [0x0bu8, 0x0c, 0x12, 0x12, 0x34, 0x56, 0x78, b't', b'e', b's', b't']
+            code: ReturnCode::Accepted,
+        };
+        assert_eq!(ack.check(), Ok((0x1234, 0x5678)));
+        ack.code = ReturnCode::Rejected(RejectedReason::InvalidTopicId);
+        assert_eq!(
+            ack.check(),
+            Err((RejectedReason::InvalidTopicId, 0x1234, 0x5678))
+        );
+        let ack = RegAck {
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            code: ReturnCode::Rejected(RejectedReason::Congestion),
+            extra: ByteBuf::new(),
+        };
+        assert_eq!(
+            ack.check(),
+            Err((RejectedReason::Congestion, 0x1234, 0x5678))
         );
-        let actual: Message = bytes.read(&mut 0).unwrap();
-        assert_eq!(actual, expected);
     }
 
     #[test]
@@ -1325,12 +7050,28 @@ mod tests {
         bytes.write(&mut len, expected.clone()).unwrap();
         assert_eq_hex!(
             &bytes[..len],
-            [0x07u8, 0x13, 0x00, 0x12, 0x34, 0x56, 0x78, 0x02]
+            [0x08u8, 0x13, 0x00, 0x12, 0x34, 0x56, 0x78, 0x02]
         );
         let actual: Message = bytes.read(&mut 0).unwrap();
         assert_eq!(actual, expected);
     }
     #[test]
+    fn suback_length_byte() {
+        // The length byte has to cover all 8 bytes, or a peer reads a
+        // truncated SUBACK.
+        let mut bytes = [0u8; 20];
+        let mut len = 0usize;
+        let msg = SubAck {
+            flags: Flags::default(),
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            code: RejectedReason::InvalidTopicId.into(),
+        };
+        bytes.write(&mut len, msg).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(bytes[0] as usize, len);
+    }
+    #[test]
     fn unsubscribe_encode_parse_id() {
         let mut bytes = [0u8; 20];
         let mut len = 0usize;
@@ -1384,7 +7125,7 @@ mod tests {
         let mut bytes = [0u8; 20];
         let mut len = 0usize;
         let expected = Message::PingReq(PingReq {
-            client_id: ClientId::from("test-client"),
+            client_id: Some(ClientId::from("test-client")),
         });
         bytes.write(&mut len, expected.clone()).unwrap();
         assert_eq_hex!(
@@ -1395,6 +7136,28 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn pingreq_client_id_boundary() {
+        let actual: Message = [0x02u8, 0x16].read(&mut 0).unwrap();
+        assert_eq!(actual, Message::ping_req(None));
+        let actual: Message = [0x03u8, 0x16, b'a'].read(&mut 0).unwrap();
+        assert_eq!(actual, Message::ping_req(Some("a")));
+        // A one-byte length of 0 must hit the `len < 2` guard rather than
+        // underflow when sizing the client id.
+        assert_eq!(
+            [0x00u8, 0x16].read::<PingReq>(&mut 0),
+            Err(byte::Error::BadInput {
+                err: "Len must be at least 2 bytes"
+            })
+        );
+        assert!([0x00u8, 0x16].read::<Message>(&mut 0).is_err());
+
+        let mut bytes = [0u8; 4];
+        let mut len = 0usize;
+        bytes.write(&mut len, Message::ping_req(None)).unwrap();
+        assert_eq_hex!(&bytes[..len], [0x02u8, 0x16]);
+    }
+
     #[test]
     fn pingresp_encode_parse() {
         let mut bytes = [0u8; 20];
@@ -1406,4 +7169,200 @@ mod tests {
         let actual: Message = bytes.read(&mut 0).unwrap();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn batch_encode_parse() {
+        let expected = [
+            Message::SearchGw(SearchGw { radius: 5 }),
+            Message::PingReq(PingReq {
+                client_id: Some(ClientId::from("test-client")),
+            }),
+            Message::PingResp(PingResp {}),
+        ];
+        let bytes = encode_batch::<32>(&expected).unwrap();
+        assert_eq!(bytes.len(), 3 + 13 + 2);
+        let offset = &mut 0;
+        for msg in expected.iter() {
+            let actual: Message = bytes.read(offset).unwrap();
+            assert_eq!(&actual, msg);
+        }
+        assert_eq!(*offset, bytes.len());
+    }
+
+    // Messages live on the stack, so keep an eye on their size. If a change
+    // trips these, consider whether the new variant really needs to be inline
+    // before raising the budget. Sizes are for a 64-bit host.
+    #[test]
+    fn message_size_budget() {
+        assert!(core::mem::size_of::<Message>() <= 320);
+        assert!(core::mem::size_of::<ForwardedMessage>() <= 352);
+        assert!(core::mem::size_of::<MaybeForwardedMessage>() <= 352);
+    }
+
+    #[test]
+    fn message_try_into_bytes() {
+        let bytes = TryInto::<heapless::Vec<u8, 32>>::try_into(PingResp {}).unwrap();
+        assert_eq_hex!(&bytes[..], [0x02u8, 0x17]);
+        let bytes: heapless::Vec<u8, 32> = Message::search_gw(5).try_into().unwrap();
+        assert_eq_hex!(&bytes[..], [0x03u8, 0x01, 0x05]);
+        assert_eq!(
+            TryInto::<heapless::Vec<u8, 2>>::try_into(SearchGw { radius: 5 }),
+            Err(byte::Error::Incomplete)
+        );
+    }
+
+    #[test]
+    fn message_encode_exact() {
+        let msg = Message::publish(Flags::default(), 0x1234, 0x5678, "test");
+        let mut bytes = [0xaau8; 10];
+        assert_eq!(msg.encode_exact(&mut bytes), Err(byte::Error::Incomplete));
+        assert_eq!(bytes, [0xaa; 10]);
+        let mut bytes = [0xaau8; 11];
+        assert_eq!(msg.encode_exact(&mut bytes), Ok(11));
+    }
+
+    #[test]
+    fn message_decode_into_reuses_publish() {
+        let mut buf = [0u8; 32];
+        let mut msg = Message::default();
+        let first = Message::publish(Flags::default(), 1, 2, "first payload");
+        let len = first.encode(&mut buf).unwrap();
+        assert_eq!(msg.decode_into(&buf[..len]), Ok(len));
+        assert_eq!(msg, first);
+
+        let second = Message::publish(Flags(0x20), 3, 4, "second");
+        let len = second.encode(&mut buf).unwrap();
+        assert_eq!(msg.decode_into(&buf[..len]), Ok(len));
+        assert_eq!(msg, second);
+
+        assert!(msg.decode_into(&buf[..len - 1]).is_err());
+        assert_eq!(msg, second);
+        let len = Message::ping_resp().encode(&mut buf).unwrap();
+        assert_eq!(msg.decode_into(&buf[..len]), Ok(len));
+        assert_eq!(msg, Message::ping_resp());
+    }
+
+    #[test]
+    fn message_body_len() {
+        assert_eq!(Message::ping_resp().body_len(), 1);
+        for payload_len in [0, 4, 248, 249, 256] {
+            let payload = [0u8; 256];
+            let msg = Message::Publish(
+                Publish::new_qos(QoS::AtMostOnce, 1, None, &payload[..payload_len]).unwrap(),
+            );
+            assert_eq!(msg.body_len(), 6 + payload_len);
+        }
+    }
+
+    #[test]
+    fn message_encoded_len() {
+        let msgs = [
+            Message::search_gw(5),
+            Message::connect("test-client", 30, true, false),
+            Message::conn_ack(ReturnCode::Accepted),
+            Message::will_topic("will", QoS::AtLeastOnce, true),
+            Message::register(1, 2, "test"),
+            Message::reg_ack(1, 2, ReturnCode::Accepted),
+            Message::publish(Flags::default(), 1, 2, "test"),
+            Message::pub_ack(1, 2, ReturnCode::Accepted),
+            Message::subscribe(Flags::default(), 1, TopicNameOrId::Name("a/#".into())),
+            Message::sub_ack(Flags::default(), 1, 2, ReturnCode::Accepted),
+            Message::unsubscribe(Flags(0x01), 1, TopicNameOrId::Id(2)),
+            Message::unsub_ack(1, ReturnCode::Accepted),
+            Message::ping_req(Some("test")),
+            Message::ping_resp(),
+        ];
+        let mut bytes = [0u8; 300];
+        for msg in msgs.iter() {
+            let len = msg.encode_exact(&mut bytes).unwrap();
+            assert_eq!(len, msg.encoded_len(), "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn batch_encode_overflow() {
+        let msgs = [
+            Message::PingResp(PingResp {}),
+            Message::PingResp(PingResp {}),
+        ];
+        assert_eq!(encode_batch::<3>(&msgs), Err(byte::Error::Incomplete));
+    }
+
+    #[test]
+    fn message_buf_push_parse() {
+        let mut buf = MessageBuf::<6>::new();
+        buf.try_push(&Message::SearchGw(SearchGw { radius: 5 }))
+            .unwrap();
+        buf.try_push(&Message::PingResp(PingResp {})).unwrap();
+        assert_eq!(
+            buf.try_push(&Message::SearchGw(SearchGw { radius: 5 })),
+            Err(byte::Error::Incomplete)
+        );
+        assert_eq_hex!(buf.as_slice(), [0x03u8, 0x01, 0x05, 0x02, 0x17]);
+        let offset = &mut 0;
+        let first: Message = buf.as_slice().read(offset).unwrap();
+        let second: Message = buf.as_slice().read(offset).unwrap();
+        assert_eq!(first, Message::SearchGw(SearchGw { radius: 5 }));
+        assert_eq!(second, Message::PingResp(PingResp {}));
+    }
+
+    #[test]
+    fn message_iter_parse() {
+        let bytes = encode_batch::<32>(&[
+            Message::SearchGw(SearchGw { radius: 5 }),
+            Message::PingResp(PingResp {}),
+        ])
+        .unwrap();
+        let mut iter = MessageIter::new(&bytes, 10);
+        assert_eq!(
+            iter.next(),
+            Some(Ok(Message::SearchGw(SearchGw { radius: 5 })))
+        );
+        assert_eq!(iter.next(), Some(Ok(Message::PingResp(PingResp {}))));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn message_iter_max_messages() {
+        let mut bytes = [0u8; 200];
+        let offset = &mut 0;
+        for _ in 0..100 {
+            bytes.write(offset, PingResp {}).unwrap();
+        }
+        let mut iter = MessageIter::new(&bytes, 10);
+        for _ in 0..10 {
+            assert_eq!(iter.next(), Some(Ok(Message::PingResp(PingResp {}))));
+        }
+        assert!(matches!(
+            iter.next(),
+            Some(Err(byte::Error::BadInput { .. }))
+        ));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn publish_affects_topic_id() {
+        let mut flags = Flags::default();
+        flags.set_topic_id_type(TopicIdType::Normal.into());
+        let msg = Message::Publish(Publish {
+            flags,
+            topic_id: 0x1234,
+            msg_id: 0x5678,
+            data: PublishData::from("test"),
+        });
+        assert_eq!(msg.affects_topic_id(), Some(0x1234));
+    }
+
+    #[test]
+    fn publish_short_name_affects_no_topic_id() {
+        let mut flags = Flags::default();
+        flags.set_topic_id_type(TopicIdType::ShortName.into());
+        let msg = Message::Publish(Publish {
+            flags,
+            topic_id: u16::from_be_bytes(*b"ab"),
+            msg_id: 0x5678,
+            data: PublishData::from("test"),
+        });
+        assert_eq!(msg.affects_topic_id(), None);
+    }
 }
@@ -0,0 +1,359 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::defs::{GwAddress, GwInfo, SearchGw};
+
+/// Drives gateway discovery for a client: broadcasts SEARCHGW every
+/// `interval_ms` with a radius that grows by one per attempt up to
+/// `max_radius`, until a GWINFO is seen. Times are milliseconds on any
+/// monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DiscoveryState {
+    radius: u8,
+    max_radius: u8,
+    attempts: u32,
+    interval_ms: u64,
+    next_search: u64,
+    gw_id: Option<u8>,
+    max_delay_ms: u16,
+}
+
+impl DiscoveryState {
+    /// Default upper bound on the random delay before a SEARCHGW, the
+    /// spec's Tsearchgw.
+    pub const T_SEARCHGW_MS: u16 = 5000;
+
+    pub fn new(max_radius: u8, interval_ms: u64) -> Self {
+        Self {
+            radius: SearchGw::broadcast().radius,
+            max_radius,
+            attempts: 0,
+            interval_ms,
+            next_search: 0,
+            gw_id: None,
+            max_delay_ms: Self::T_SEARCHGW_MS,
+        }
+    }
+
+    /// Sets the upper bound used by [`DiscoveryState::next_search_delay`].
+    pub fn with_max_delay(mut self, max_delay_ms: u16) -> Self {
+        self.max_delay_ms = max_delay_ms;
+        self
+    }
+
+    /// Maps a caller-supplied random value onto a delay in milliseconds,
+    /// from 0 up to and including the configured maximum, to wait before
+    /// sending a SEARCHGW. Spreading clients out this way avoids broadcast
+    /// storms.
+    pub fn next_search_delay(&self, rng_value: u16) -> u16 {
+        jitter(rng_value, self.max_delay_ms)
+    }
+
+    /// Returns the SEARCHGW to send if one is due at `now`. Returns `None`
+    /// once a gateway has been found.
+    pub fn poll(&mut self, now: u64) -> Option<SearchGw> {
+        if self.gw_id.is_some() || now < self.next_search {
+            return None;
+        }
+        let msg = SearchGw {
+            radius: self.radius,
+        };
+        self.attempts += 1;
+        self.next_search = now + self.interval_ms;
+        self.radius = self.radius.saturating_add(1).min(self.max_radius);
+        Some(msg)
+    }
+
+    /// Records a GWINFO answering the search, which ends discovery.
+    pub fn on_gw_info(&mut self, msg: &GwInfo) {
+        self.gw_id = Some(msg.gw_id);
+    }
+
+    /// Records an ADVERTISE from gateway `gw_id`, which also ends discovery.
+    /// ADVERTISE isn't parsed by this crate, so the caller passes the id
+    /// read from it.
+    pub fn on_advertise(&mut self, gw_id: u8) {
+        self.gw_id = Some(gw_id);
+    }
+
+    /// The radius the next SEARCHGW will use.
+    pub fn radius(&self) -> u8 {
+        self.radius
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The id of the gateway found, if any.
+    pub fn gateway(&self) -> Option<u8> {
+        self.gw_id
+    }
+}
+
+/// Maps a random value onto `0..=max`.
+fn jitter(rng_value: u16, max: u16) -> u16 {
+    ((rng_value as u32 * (max as u32 + 1)) >> 16) as u16
+}
+
+/// Gateway side of discovery: answers a SEARCHGW with a GWINFO after a
+/// random delay, and stays quiet if another gateway answers first. Times are
+/// milliseconds on any monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SearchGwResponder {
+    gw_id: u8,
+    max_delay_ms: u16,
+    pending: Option<u64>,
+}
+
+impl SearchGwResponder {
+    pub fn new(gw_id: u8, max_delay_ms: u16) -> Self {
+        Self {
+            gw_id,
+            max_delay_ms,
+            pending: None,
+        }
+    }
+
+    /// Schedules a GWINFO after a delay derived from the caller-supplied
+    /// `rng_value`, as in [`DiscoveryState::next_search_delay`]. A SEARCHGW
+    /// arriving while a response is already pending doesn't reschedule it.
+    pub fn on_search_gw(&mut self, now: u64, rng_value: u16) {
+        if self.pending.is_none() {
+            self.pending = Some(now + jitter(rng_value, self.max_delay_ms) as u64);
+        }
+    }
+
+    /// Cancels the pending response when another gateway has answered.
+    /// Our own GWINFO, echoed back by the broadcast, is ignored.
+    pub fn on_gw_info(&mut self, msg: &GwInfo) {
+        if msg.gw_id != self.gw_id {
+            self.pending = None;
+        }
+    }
+
+    /// Returns the GWINFO to broadcast if the response is due at `now`.
+    pub fn poll(&mut self, now: u64) -> Option<GwInfo> {
+        match self.pending {
+            Some(due) if now >= due => {
+                self.pending = None;
+                Some(GwInfo {
+                    gw_id: self.gw_id,
+                    gw_add: None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a response is scheduled.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+/// A gateway learned during discovery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GatewayEntry {
+    pub gw_id: u8,
+    pub gw_add: Option<GwAddress>,
+    /// When the entry lapses unless refreshed, in milliseconds.
+    pub expires_at: u64,
+}
+
+/// Up to `N` gateways, one entry per gw_id. Times are milliseconds on the
+/// same clock as [`DiscoveryState`].
+#[derive(Clone, Debug, Default)]
+pub struct GatewayTable<const N: usize> {
+    entries: heapless::Vec<GatewayEntry, N>,
+}
+
+impl<const N: usize> GatewayTable<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records gateway `gw_id` as alive for `duration` seconds from `now`.
+    /// A repeat gw_id refreshes the existing entry, keeping its address if
+    /// `gw_add` is `None`. When the table is full, the entry closest to
+    /// expiring makes room.
+    pub fn upsert(&mut self, gw_id: u8, gw_add: Option<GwAddress>, duration: u16, now: u64) {
+        let expires_at = now + duration as u64 * 1000;
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.gw_id == gw_id) {
+            entry.expires_at = expires_at;
+            if gw_add.is_some() {
+                entry.gw_add = gw_add;
+            }
+            return;
+        }
+        let entry = GatewayEntry {
+            gw_id,
+            gw_add,
+            expires_at,
+        };
+        if self.entries.is_full() {
+            if let Some(oldest) =
+                (0..self.entries.len()).min_by_key(|i| self.entries[*i].expires_at)
+            {
+                self.entries[oldest] = entry;
+            }
+            return;
+        }
+        self.entries.push(entry).ok();
+    }
+
+    /// Like [`GatewayTable::upsert`] for a GWINFO, which carries no duration
+    /// of its own.
+    pub fn on_gw_info(&mut self, msg: &GwInfo, duration: u16, now: u64) {
+        self.upsert(msg.gw_id, msg.gw_add, duration, now);
+    }
+
+    /// Drops entries whose duration has run out by `now`.
+    pub fn expire(&mut self, now: u64) {
+        self.entries.retain(|e| e.expires_at > now);
+    }
+
+    pub fn get(&self, gw_id: u8) -> Option<&GatewayEntry> {
+        self.entries.iter().find(|e| e.gw_id == gw_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &GatewayEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_escalates_radius() {
+        let mut state = DiscoveryState::new(3, 1000);
+        assert_eq!(state.poll(0), Some(SearchGw { radius: 0 }));
+        assert_eq!(state.poll(500), None);
+        assert_eq!(state.poll(1000), Some(SearchGw { radius: 1 }));
+        assert_eq!(state.attempts(), 2);
+        assert_eq!(state.radius(), 2);
+
+        state.on_gw_info(&GwInfo {
+            gw_id: 7,
+            gw_add: None,
+        });
+        assert_eq!(state.poll(2000), None);
+        assert_eq!(state.gateway(), Some(7));
+    }
+
+    #[test]
+    fn discovery_ends_on_advertise() {
+        let mut state = DiscoveryState::new(3, 1000);
+        assert_eq!(state.poll(0), Some(SearchGw { radius: 0 }));
+        state.on_advertise(4);
+        assert_eq!(state.poll(1000), None);
+        assert_eq!(state.gateway(), Some(4));
+    }
+
+    #[test]
+    fn discovery_radius_capped() {
+        let mut state = DiscoveryState::new(1, 10);
+        assert_eq!(state.poll(0), Some(SearchGw { radius: 0 }));
+        assert_eq!(state.poll(10), Some(SearchGw { radius: 1 }));
+        assert_eq!(state.poll(20), Some(SearchGw { radius: 1 }));
+    }
+
+    #[test]
+    fn search_delay_bounded() {
+        let state = DiscoveryState::new(3, 1000);
+        assert_eq!(state.next_search_delay(0), 0);
+        assert_eq!(
+            state.next_search_delay(u16::MAX),
+            DiscoveryState::T_SEARCHGW_MS
+        );
+        assert!(state.next_search_delay(u16::MAX / 2) < DiscoveryState::T_SEARCHGW_MS);
+
+        let state = state.with_max_delay(u16::MAX);
+        assert_eq!(state.next_search_delay(0), 0);
+        assert_eq!(state.next_search_delay(u16::MAX), u16::MAX);
+
+        let state = state.with_max_delay(0);
+        assert_eq!(state.next_search_delay(u16::MAX), 0);
+    }
+
+    #[test]
+    fn responder_answers_after_delay() {
+        let mut responder = SearchGwResponder::new(4, 1000);
+        responder.on_search_gw(100, u16::MAX);
+        responder.on_search_gw(200, 0);
+        assert_eq!(responder.poll(1099), None);
+        assert_eq!(
+            responder.poll(1100),
+            Some(GwInfo {
+                gw_id: 4,
+                gw_add: None,
+            })
+        );
+        assert!(!responder.is_pending());
+        assert_eq!(responder.poll(2000), None);
+    }
+
+    #[test]
+    fn responder_suppressed_by_competing_gw_info() {
+        let mut responder = SearchGwResponder::new(4, 1000);
+        responder.on_search_gw(0, u16::MAX / 2);
+        responder.on_gw_info(&GwInfo {
+            gw_id: 4,
+            gw_add: None,
+        });
+        assert!(responder.is_pending());
+        responder.on_gw_info(&GwInfo {
+            gw_id: 9,
+            gw_add: None,
+        });
+        assert!(!responder.is_pending());
+        assert_eq!(responder.poll(1000), None);
+    }
+
+    #[test]
+    fn gateway_table_upsert() {
+        let mut table = GatewayTable::<2>::new();
+        let add = GwAddress::try_from(&[10, 0, 0, 1][..]).unwrap();
+        table.upsert(1, Some(add), 60, 0);
+        table.upsert(1, None, 900, 1000);
+        assert_eq!(table.len(), 1);
+        assert_eq!(
+            table.get(1),
+            Some(&GatewayEntry {
+                gw_id: 1,
+                gw_add: Some(add),
+                expires_at: 901_000,
+            })
+        );
+
+        table.upsert(2, None, 10, 1000);
+        table.expire(11_000);
+        assert!(table.get(2).is_none());
+        assert!(table.get(1).is_some());
+    }
+
+    #[test]
+    fn gateway_table_full_replaces_soonest_expiry() {
+        let mut table = GatewayTable::<2>::new();
+        table.upsert(1, None, 100, 0);
+        table.upsert(2, None, 10, 0);
+        table.upsert(3, None, 50, 0);
+        assert_eq!(table.len(), 2);
+        assert!(table.get(2).is_none());
+        assert!(table.get(3).is_some());
+    }
+}
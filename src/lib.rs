@@ -6,5 +6,13 @@
 #![deny(warnings)]
 #![allow(dead_code)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod borrowed;
 pub mod defs;
+pub mod discovery;
+pub mod sleep;
+#[cfg(feature = "std")]
+pub mod udp;
 pub use defs::*;
@@ -0,0 +1,141 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::defs::{Connect, Disconnect, Message, PingReq};
+
+/// Where a client is in the sleep cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SleepPhase {
+    Active,
+    /// A DISCONNECT with a duration was sent; waiting for the gateway's.
+    GoingToSleep,
+    Asleep,
+    /// A PINGREQ was sent on waking; the gateway delivers buffered messages
+    /// and then sends a PINGRESP.
+    Awake,
+    /// The gateway sent a DISCONNECT that didn't acknowledge a sleep request.
+    Disconnected,
+}
+
+/// Tracks a client through the sleep cycle: DISCONNECT with a duration to
+/// fall asleep, PINGREQ to wake up and collect buffered messages, CONNECT to
+/// become active again. Only builds messages and reacts to incoming ones;
+/// sending them is up to the caller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SleepState {
+    phase: SleepPhase,
+    duration: u16,
+}
+
+impl Default for SleepState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SleepState {
+    pub fn new() -> Self {
+        Self {
+            phase: SleepPhase::Active,
+            duration: 0,
+        }
+    }
+
+    /// Returns the DISCONNECT asking the gateway to keep the session while
+    /// the client sleeps for `duration` seconds.
+    pub fn go_to_sleep(&mut self, duration: u16) -> Disconnect {
+        self.phase = SleepPhase::GoingToSleep;
+        self.duration = duration;
+        Disconnect {
+            duration: Some(duration),
+        }
+    }
+
+    /// Returns the PINGREQ that wakes the client to collect buffered
+    /// messages.
+    pub fn wake(&mut self, client_id: &str) -> PingReq {
+        self.phase = SleepPhase::Awake;
+        PingReq {
+            client_id: Some(client_id.into()),
+        }
+    }
+
+    /// Returns the CONNECT that ends the sleep cycle, keeping the session.
+    pub fn resume(&mut self, client_id: &str, duration: u16) -> Connect {
+        self.phase = SleepPhase::Active;
+        Connect::resume(client_id, duration)
+    }
+
+    /// Advances the state on an incoming DISCONNECT or PINGRESP. Returns
+    /// whether `msg` was one of them; other messages are left to the caller.
+    pub fn on_message(&mut self, msg: &Message) -> bool {
+        match (self.phase, msg) {
+            (SleepPhase::GoingToSleep, Message::Disconnect(_)) => {
+                self.phase = SleepPhase::Asleep;
+            }
+            (_, Message::Disconnect(_)) => self.phase = SleepPhase::Disconnected,
+            (SleepPhase::Awake, Message::PingResp(_)) => self.phase = SleepPhase::Asleep,
+            (_, Message::PingResp(_)) => {}
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn phase(&self) -> SleepPhase {
+        self.phase
+    }
+
+    /// The sleep duration last requested, in seconds, or `None` while active.
+    pub fn duration(&self) -> Option<u16> {
+        match self.phase {
+            SleepPhase::Active | SleepPhase::Disconnected => None,
+            _ => Some(self.duration),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleep_wake_resume() {
+        let mut state = SleepState::new();
+        assert_eq!(state.duration(), None);
+
+        let msg = state.go_to_sleep(600);
+        assert_eq!(
+            msg,
+            Disconnect {
+                duration: Some(600)
+            }
+        );
+        assert_eq!(state.phase(), SleepPhase::GoingToSleep);
+        assert!(state.on_message(&Message::disconnect(None)));
+        assert_eq!(state.phase(), SleepPhase::Asleep);
+        assert_eq!(state.duration(), Some(600));
+
+        let msg = state.wake("sensor");
+        assert_eq!(Message::from(msg), Message::ping_req(Some("sensor")));
+        assert_eq!(state.phase(), SleepPhase::Awake);
+        assert!(!state.on_message(&Message::publish(Default::default(), 1, 0, "x")));
+        assert_eq!(state.phase(), SleepPhase::Awake);
+        assert!(state.on_message(&Message::ping_resp()));
+        assert_eq!(state.phase(), SleepPhase::Asleep);
+
+        let msg = state.resume("sensor", 30);
+        assert_eq!(msg, Connect::resume("sensor", 30));
+        assert_eq!(state.phase(), SleepPhase::Active);
+        assert_eq!(state.duration(), None);
+    }
+
+    #[test]
+    fn unsolicited_disconnect() {
+        let mut state = SleepState::new();
+        assert!(state.on_message(&Message::disconnect(None)));
+        assert_eq!(state.phase(), SleepPhase::Disconnected);
+    }
+}
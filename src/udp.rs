@@ -0,0 +1,100 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::defs::{
+    parse_exact, DecodeError, ForwardedMessageIter, MaybeForwardedMessage, Message, SCRATCH_LEN,
+};
+
+/// Receives one datagram into `buf` and parses it as a single message.
+/// Malformed datagrams are reported as [`io::ErrorKind::InvalidData`].
+pub fn recv_message(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(Message, SocketAddr)> {
+    let (len, addr) = socket.recv_from(buf)?;
+    let msg =
+        parse_exact(&buf[..len]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((msg, addr))
+}
+
+/// Receives one datagram into `buf` and parses every message packed in it,
+/// forwarded or not. A datagram holding more than `N` messages, or any
+/// malformed one, is reported as [`io::ErrorKind::InvalidData`].
+pub fn recv_messages<const N: usize>(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(heapless::Vec<MaybeForwardedMessage, N>, SocketAddr)> {
+    let (len, addr) = socket.recv_from(buf)?;
+    let mut msgs = heapless::Vec::new();
+    for msg in ForwardedMessageIter::new_forwarded(&buf[..len], N) {
+        let msg =
+            msg.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, DecodeError::from(e)))?;
+        // Cannot overflow: the iterator yields at most N messages.
+        msgs.push(msg).ok();
+    }
+    Ok((msgs, addr))
+}
+
+/// Encodes `msg` and sends it to `addr` as one datagram, returning the
+/// number of bytes sent.
+pub fn send_message<A: ToSocketAddrs>(
+    socket: &UdpSocket,
+    msg: &Message,
+    addr: A,
+) -> io::Result<usize> {
+    let mut buf = [0u8; SCRATCH_LEN];
+    let len = msg
+        .encode(&mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    socket.send_to(&buf[..len], addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defs::{ForwardedMessage, WirelessNodeId};
+    use byte::BytesExt;
+
+    #[test]
+    fn loopback_ping_req() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let msg = Message::ping_req(Some("client"));
+        assert_eq!(
+            send_message(&client, &msg, server.local_addr().unwrap()).unwrap(),
+            8
+        );
+        let mut buf = [0u8; 64];
+        let (received, from) = recv_message(&server, &mut buf).unwrap();
+        assert_eq!(received, msg);
+        assert_eq!(from, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn loopback_two_forwarded_frames() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let first = MaybeForwardedMessage::ForwardedMessage(ForwardedMessage {
+            ctrl: 0x00,
+            wireless_node_id: WirelessNodeId::from("a"),
+            message: Message::ping_req(None),
+        });
+        let second = MaybeForwardedMessage::ForwardedMessage(ForwardedMessage {
+            ctrl: 0x00,
+            wireless_node_id: WirelessNodeId::from("bb"),
+            message: Message::ping_req(Some("node")),
+        });
+        let mut datagram = [0u8; 64];
+        let mut len = 0usize;
+        datagram.write(&mut len, &first).unwrap();
+        datagram.write(&mut len, &second).unwrap();
+        client
+            .send_to(&datagram[..len], server.local_addr().unwrap())
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let (msgs, _) = recv_messages::<4>(&server, &mut buf).unwrap();
+        assert_eq!(msgs.as_slice(), [first, second]);
+    }
+}